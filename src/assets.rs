@@ -0,0 +1,508 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+
+use crate::diagnostics::{self, Diagnostic};
+use crate::links::xhtml_files;
+use crate::media::{self, Kind};
+use crate::render::is_external;
+use crate::types::{Asset, FilesConfig, Page};
+use crate::util::{glob_match, percent_decode, sanitize_name};
+
+// Manifest id of the cover image
+const COVER_ID: &str = "cover";
+
+// A file the book refers to: its path inside OPS, and where the reference is
+struct Reference {
+    href: String,
+    target: String,
+    file: String,
+    position: Option<(usize, usize)>,
+}
+
+impl Reference {
+    fn report(&self, message: String) {
+        let diagnostic = Diagnostic::warning(message);
+        match self.position {
+            Some((line, column)) => diagnostic.at(&self.file, line, column).emit(),
+            None => diagnostic.in_file(&self.file).emit(),
+        }
+    }
+}
+
+// Find the images, fonts, stylesheets, scripts, audio and video in the book
+// folder and its subfolders, as picked by the `files` globs of book.yaml.
+// Files at the top go to the folder for their kind (images/, fonts/...);
+// files in subfolders keep their path. Files are told apart by their content
+// where it has a signature, and a non-core type gets a core file with the
+// same name as fallback when there is one.
+pub fn scan_assets(book_folder: &str, config: &FilesConfig, dest_path: &Path) -> Vec<Asset> {
+    let root = Path::new(book_folder);
+    // the output may live inside the book folder; never pick it up again
+    let dest_folder = dest_path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let skip = match (fs::canonicalize(dest_folder), fs::canonicalize(root)) {
+        (Ok(dest_folder), Ok(root)) if dest_folder != root => Some(dest_folder),
+        _ => fs::canonicalize(dest_path).ok(),
+    };
+
+    let mut assets: Vec<Asset> = Vec::new();
+
+    for path in walk(root, skip.as_deref()) {
+        let relative = path.strip_prefix(root).unwrap_or(&path).to_string_lossy().replace('\\', "/");
+
+        if !picked(config, &relative) {
+            continue;
+        }
+
+        let Some(media_type) = media::identify(&path) else {
+            continue;
+        };
+
+        if let Some(declared) = media::from_extension(&path).filter(|declared| declared.mime != media_type.mime) {
+            Diagnostic::warning(format!(
+                "{} has the extension of {} but its content is {}",
+                relative, declared.mime, media_type.mime
+            ))
+            .in_file(&relative)
+            .emit();
+        }
+
+        let href = if relative.contains('/') {
+            relative.clone()
+        } else {
+            format!("{}/{}", media_type.kind.folder(), relative)
+        };
+
+        if let Some(other) = assets.iter().find(|asset| asset.href == href) {
+            Diagnostic::warning(format!("{} would overwrite {} as {}, leaving it out", relative, other.source, href))
+                .in_file(&relative)
+                .emit();
+            continue;
+        }
+
+        let id = manifest_id(&href, media_type.kind, &assets);
+        assets.push(Asset { source: relative, href, id, media_type, fallback: None });
+    }
+
+    for index in 0..assets.len() {
+        let asset = &assets[index];
+        if asset.media_type.core || asset.media_type.kind == Kind::Video {
+            continue;
+        }
+
+        let stem = Path::new(&asset.source).with_extension("");
+        let fallback = assets.iter().find(|other| {
+            other.media_type.core
+                && other.media_type.kind == asset.media_type.kind
+                && Path::new(&other.source).with_extension("") == stem
+        });
+
+        match fallback {
+            Some(fallback) => assets[index].fallback = Some(fallback.id.clone()),
+            None => Diagnostic::warning(format!(
+                "{} is {}, which not every reading system supports; convert it to {} or add one with the same name as a fallback",
+                asset.source,
+                asset.media_type.mime,
+                media::core_alternatives(asset.media_type.kind)
+            ))
+            .in_file(&asset.source)
+            .emit(),
+        }
+    }
+
+    assets
+}
+
+// Whether the `files` globs take a file: an exclude glob wins over an
+// include one
+fn picked(config: &FilesConfig, relative: &str) -> bool {
+    let included = config.include.is_empty() || config.include.iter().any(|glob| glob_match(glob, relative));
+    let excluded = config.exclude.iter().any(|glob| glob_match(glob, relative));
+    included && !excluded
+}
+
+// The cover image, which reading systems show in their library
+pub fn cover(assets: &[Asset]) -> Option<&Asset> {
+    assets.iter().find(|asset| asset.id == COVER_ID)
+}
+
+// Where a stylesheet of the book folder is copied to inside OPS
+pub fn stylesheet_href(assets: &[Asset], path: &str) -> Option<String> {
+    let path = path.trim_start_matches("./");
+    assets
+        .iter()
+        .find(|asset| asset.source == path && asset.media_type.kind == Kind::Style)
+        .map(|asset| asset.href.clone())
+}
+
+// Where an image named in book.yaml is inside OPS. The name is relative to
+// the book folder, or to its images folder as it used to be.
+pub fn image_href(assets: &[Asset], path: &str) -> Option<String> {
+    let path = path.trim_start_matches("./");
+    let images = format!("images/{}", path);
+    assets
+        .iter()
+        .filter(|asset| asset.media_type.kind == Kind::Image)
+        .find(|asset| asset.source == path)
+        .or_else(|| assets.iter().find(|asset| asset.media_type.kind == Kind::Image && asset.source == images))
+        .map(|asset| asset.href.clone())
+}
+
+// The stylesheets every page links, as set by `styles` in book.yaml
+pub fn book_stylesheets(styles: Option<&[String]>, assets: &[Asset]) -> Vec<String> {
+    let Some(styles) = styles else {
+        return stylesheet_href(assets, "book.css").into_iter().collect();
+    };
+
+    styles
+        .iter()
+        .filter_map(|style| {
+            let href = stylesheet_href(assets, style);
+            if href.is_none() {
+                Diagnostic::warning(format!("stylesheet {} is not in the book folder", style)).in_config(style).emit();
+            }
+            href
+        })
+        .collect()
+}
+
+// Keep the assets the generated pages refer to, and those the stylesheets
+// they use refer to in turn, along with their fallbacks. Files nobody refers
+// to are reported and left out unless `keep_unused` is set, and references
+// to files that are not in the book are reported where they were written.
+// Links to other pages are left to the link checker.
+pub fn referenced_assets(
+    book_folder: &str,
+    dest_path: &str,
+    assets: Vec<Asset>,
+    pages: &[Page],
+    generated: &[String],
+    keep_unused: bool,
+) -> Vec<Asset> {
+    let ops_path = Path::new(dest_path).join("OPS");
+    let mut references: Vec<Reference> = Vec::new();
+
+    for file_path in xhtml_files(&ops_path) {
+        let Ok(content) = fs::read_to_string(&file_path) else {
+            continue;
+        };
+        let name = file_path.strip_prefix(&ops_path).unwrap_or(&file_path).to_string_lossy().replace('\\', "/");
+        let page = pages.iter().find(|page| name == format!("content/{}.xhtml", page.file));
+        // how many references with each href have been seen, to find the right one in the chapter
+        let mut seen: HashMap<String, usize> = HashMap::new();
+
+        for href in xhtml_references(&content) {
+            let Some(target) = resolve(&name, &href) else {
+                continue;
+            };
+
+            let written = percent_decode(&href.replace("&amp;", "&"));
+            let occurrence = seen.entry(written.clone()).or_insert(0);
+            *occurrence += 1;
+            let source_link = page.and_then(|page| {
+                page.links.iter().filter(|link| percent_decode(&link.href) == written).nth(*occurrence - 1).map(|link| (page, link))
+            });
+
+            references.push(match source_link {
+                Some((page, link)) => Reference { href: href.clone(), target, file: page.source.clone(), position: Some((link.line, link.column)) },
+                None => Reference { href: href.clone(), target, file: name.clone(), position: None },
+            });
+        }
+    }
+
+    let mut used = vec![false; assets.len()];
+    let mut scanned: Vec<String> = Vec::new();
+    // files that are not in the book: the first reference and how many there were
+    let mut missing: Vec<(usize, usize)> = Vec::new();
+    let mut index = 0;
+
+    // stylesheets add their own references to the end as they are found
+    while index < references.len() {
+        let target = references[index].target.clone();
+        index += 1;
+
+        match assets.iter().position(|asset| asset.href == target) {
+            Some(found) if !used[found] => {
+                used[found] = true;
+                let asset = &assets[found];
+                if asset.media_type.kind == Kind::Style {
+                    let content = fs::read_to_string(Path::new(book_folder).join(&asset.source)).unwrap_or_default();
+                    references.extend(css_references(&content, &asset.href, &asset.source));
+                }
+            }
+            Some(_) => {}
+            // a stylesheet mkepub wrote, such as the theme, may use the book's fonts
+            None if generated.contains(&target) => {
+                if !scanned.contains(&target) {
+                    let content = fs::read_to_string(ops_path.join(&target)).unwrap_or_default();
+                    references.extend(css_references(&content, &target, &target));
+                    scanned.push(target);
+                }
+            }
+            None => match missing.iter_mut().find(|(first, _)| references[*first].target == target) {
+                Some((_, count)) => *count += 1,
+                None => missing.push((index - 1, 1)),
+            },
+        }
+    }
+
+    for &(first, count) in &missing {
+        let reference = &references[first];
+        let more = match count {
+            1 => String::new(),
+            _ => format!(" ({} references)", count),
+        };
+        reference.report(format!("{} refers to {}, which is not in the book{}", reference.href, reference.target, more));
+    }
+
+    // a file in use needs its fallback too
+    for found in 0..assets.len() {
+        if let Some(fallback) = assets[found].fallback.as_ref().filter(|_| used[found]) {
+            if let Some(other) = assets.iter().position(|asset| &asset.id == fallback) {
+                used[other] = true;
+            }
+        }
+    }
+
+    let unused = used.iter().filter(|&&used| !used).count();
+    if keep_unused {
+        if unused > 0 {
+            diagnostics::info(&format!("Kept {} file(s) no page or stylesheet refers to", unused));
+        }
+        return assets;
+    }
+
+    assets
+        .into_iter()
+        .zip(used)
+        .filter_map(|(asset, used)| {
+            if !used {
+                Diagnostic::warning(format!("{} is not used by any page or stylesheet, leaving it out", asset.source))
+                    .in_file(&asset.source)
+                    .emit();
+            }
+            used.then_some(asset)
+        })
+        .collect()
+}
+
+// Copy the assets into OPS, returning the ones that made it
+pub fn copy_assets(book_folder: &str, dest_path: &str, assets: Vec<Asset>) -> Vec<Asset> {
+    let mut copied = Vec::with_capacity(assets.len());
+
+    for asset in assets {
+        let dest = Path::new(dest_path).join("OPS").join(&asset.href);
+        let result = match dest.parent() {
+            Some(parent) => fs::create_dir_all(parent),
+            None => Ok(()),
+        }
+        .and_then(|_| fs::copy(Path::new(book_folder).join(&asset.source), &dest));
+
+        match result {
+            Ok(_) => copied.push(asset),
+            Err(err) => Diagnostic::error(format!("failed to copy {}: {}", asset.source, err)).in_file(&asset.source).emit(),
+        }
+    }
+
+    copied
+}
+
+// Every file under `dir`, sorted, leaving out hidden files and folders
+fn walk(dir: &Path, skip: Option<&Path>) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+
+    let Ok(entries) = fs::read_dir(dir) else {
+        return files;
+    };
+
+    let mut paths: Vec<PathBuf> = entries.filter_map(Result::ok).map(|entry| entry.path()).collect();
+    paths.sort();
+
+    for path in paths {
+        let hidden = path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        if hidden {
+            continue;
+        }
+
+        if path.is_dir() {
+            if skip.is_some_and(|skip| fs::canonicalize(&path).is_ok_and(|path| path == skip)) {
+                continue;
+            }
+            files.extend(walk(&path, skip));
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+
+    files
+}
+
+// An id for the manifest item of `href`. The first image named cover keeps
+// the id `cover` it always had. Names that differ only in case or
+// punctuation (Cover.JPG, cover.jpg) get a number to tell them apart.
+fn manifest_id(href: &str, kind: Kind, assets: &[Asset]) -> String {
+    let stem = Path::new(href).file_stem().unwrap_or_default().to_string_lossy().to_lowercase();
+    if kind == Kind::Image && stem == COVER_ID && !assets.iter().any(|asset| asset.id == COVER_ID) {
+        return String::from(COVER_ID);
+    }
+
+    let base = format!("asset-{}", sanitize_name(href));
+    let mut id = base.clone();
+    let mut count = 1;
+    while assets.iter().any(|asset| asset.id == id) {
+        count += 1;
+        id = format!("{}-{}", base, count);
+    }
+    id
+}
+
+// Every src, href and url() in a generated XHTML file
+fn xhtml_references(content: &str) -> Vec<String> {
+    let reference_re = Regex::new(r#"\s(?:src|href|xlink:href|poster)="([^"]*)"|url\(\s*['"]?([^'")]*?)['"]?\s*\)"#).unwrap();
+
+    reference_re
+        .captures_iter(content)
+        .filter_map(|caps| caps.get(1).or(caps.get(2)))
+        .map(|href| href.as_str().to_string())
+        .collect()
+}
+
+// Every url() and @import in a stylesheet at `href` inside OPS, located in
+// `file`
+fn css_references(content: &str, href: &str, file: &str) -> Vec<Reference> {
+    let reference_re = Regex::new(r#"url\(\s*['"]?([^'")]*?)['"]?\s*\)|@import\s+['"]([^'"]*)['"]"#).unwrap();
+
+    reference_re
+        .captures_iter(content)
+        .filter_map(|caps| caps.get(1).or(caps.get(2)))
+        .filter_map(|written| {
+            let target = resolve(href, written.as_str())?;
+            let before = &content[..written.start()];
+            let line = before.matches('\n').count() + 1;
+            let column = before.rsplit('\n').next().unwrap_or_default().chars().count() + 1;
+            Some(Reference { href: written.as_str().to_string(), target, file: file.to_string(), position: Some((line, column)) })
+        })
+        .collect()
+}
+
+// The path inside OPS an href in `base` points to, unless it points to a
+// page, into the same file or out of the book
+fn resolve(base: &str, href: &str) -> Option<String> {
+    let href = href.replace("&amp;", "&");
+    if is_external(&href) {
+        return None;
+    }
+
+    let path = percent_decode(href.split(['#', '?']).next().unwrap_or_default());
+    if path.is_empty() || path.ends_with(".xhtml") {
+        return None;
+    }
+
+    let mut parts: Vec<&str> = base.split('/').collect();
+    parts.pop();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop()?;
+            }
+            part => parts.push(part),
+        }
+    }
+
+    Some(parts.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_ids_are_unique() {
+        let folder = std::env::temp_dir().join(format!("mkepub-ids-{}", std::process::id()));
+        fs::create_dir_all(folder.join("art")).unwrap();
+        for name in ["Cover.JPG", "cover.jpg"] {
+            fs::write(folder.join(name), b"\xFF\xD8\xFF\xE0").unwrap();
+        }
+        for name in ["a.b.png", "a-b.png", "art/a.b.png"] {
+            fs::write(folder.join(name), b"\x89PNG\r\n\x1a\n").unwrap();
+        }
+
+        let assets = scan_assets(folder.to_str().unwrap(), &FilesConfig::default(), &folder.join("out/book"));
+        let mut ids: Vec<(&str, &str)> = assets.iter().map(|asset| (asset.source.as_str(), asset.id.as_str())).collect();
+        ids.sort();
+        assert_eq!(
+            ids,
+            [
+                ("Cover.JPG", "cover"),
+                ("a-b.png", "asset-images-a-b-png"),
+                ("a.b.png", "asset-images-a-b-png-2"),
+                ("art/a.b.png", "asset-art-a-b-png"),
+                ("cover.jpg", "asset-images-cover-jpg"),
+            ]
+        );
+        assert_eq!(cover(&assets).map(|asset| asset.source.as_str()), Some("Cover.JPG"));
+
+        let _ = fs::remove_dir_all(folder);
+    }
+
+    #[test]
+    fn only_generated_files_count_as_present() {
+        let folder = std::env::temp_dir().join(format!("mkepub-references-{}", std::process::id()));
+        let ops = folder.join("out/OPS");
+        fs::create_dir_all(ops.join("content")).unwrap();
+        fs::create_dir_all(ops.join("css")).unwrap();
+        fs::create_dir_all(folder.join("fonts")).unwrap();
+        fs::write(
+            ops.join("content/a.xhtml"),
+            r#"<link href="../css/fonts.css"/><link href="../css/stale.css"/><img src="../images/ghost.png"/>"#,
+        )
+        .unwrap();
+        // fonts.css was written by this build; stale.css is left from an earlier one
+        fs::write(ops.join("css/fonts.css"), "@font-face { src: url(../fonts/a.otf) }").unwrap();
+        fs::write(ops.join("css/stale.css"), "@font-face { src: url(../fonts/b.otf) }").unwrap();
+        for name in ["a.otf", "b.otf"] {
+            fs::write(folder.join("fonts").join(name), b"OTTO").unwrap();
+        }
+
+        let assets = scan_assets(folder.to_str().unwrap(), &FilesConfig::default(), &folder.join("out"));
+        let kept = referenced_assets(
+            folder.to_str().unwrap(),
+            folder.join("out").to_str().unwrap(),
+            assets,
+            &[],
+            &[String::from("css/fonts.css")],
+            false,
+        );
+        let kept: Vec<&str> = kept.iter().map(|asset| asset.href.as_str()).collect();
+        assert_eq!(kept, ["fonts/a.otf"]);
+
+        let _ = fs::remove_dir_all(folder);
+    }
+
+    #[test]
+    fn exclude_wins_over_include() {
+        let globs = |list: &[&str]| list.iter().map(|glob| glob.to_string()).collect::<Vec<String>>();
+        let config = FilesConfig {
+            include: globs(&["images/**/*.png", "*.jpg"]),
+            exclude: globs(&["**/draft-*", "images/old/**"]),
+            keep_unused: false,
+        };
+
+        assert!(picked(&config, "images/a.png"));
+        assert!(picked(&config, "images/maps/a.png"));
+        assert!(picked(&config, "cover.jpg"));
+        assert!(picked(&config, "art/cover.jpg"));
+        assert!(!picked(&config, "images/draft-a.png"));
+        assert!(!picked(&config, "draft-cover.jpg"));
+        assert!(!picked(&config, "images/old/a.png"));
+        assert!(!picked(&config, "images/a.gif"));
+
+        // with no include globs, everything not excluded is taken
+        let config = FilesConfig { include: Vec::new(), ..config };
+        assert!(picked(&config, "fonts/a.otf"));
+        assert!(!picked(&config, "fonts/draft-a.otf"));
+    }
+}
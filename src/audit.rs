@@ -0,0 +1,318 @@
+use std::path::Path;
+
+use serde_json::json;
+
+use crate::diagnostics;
+use crate::diff::{diff, Hunk, LineMap, Op};
+use crate::types::{AuditConfig, AuditFormat};
+use crate::util::create_file;
+
+// Characters of context shown on each side of a change
+const CONTEXT: usize = 30;
+
+// Edits closer than this many characters are reported together
+const MERGE_GAP: usize = 2;
+
+// Changed hunks longer than this many characters are not diffed as a whole,
+// which would take too long; see hunk_edits
+const MAX_HUNK: usize = 10_000;
+
+// One edit made by a preprocessing rule, at the line and column of the
+// chapter file as the author wrote it
+struct Change {
+    file: String,
+    line: usize,
+    column: usize,
+    rule: String,
+    prefix: String,
+    removed: String,
+    inserted: String,
+    suffix: String,
+}
+
+// Every change made while preprocessing the book
+#[derive(Default)]
+pub struct Audit {
+    changes: Vec<Change>,
+}
+
+impl Audit {
+    // Record what `rule` changed going from `before` to `after`, given the
+    // changed lines and where the lines of `before` came from
+    pub fn record(&mut self, file: &str, rule: &str, before: &str, after: &str, line_map: &LineMap, hunks: &[Hunk]) {
+        let old_lines: Vec<&str> = before.split('\n').collect();
+        let new_lines: Vec<&str> = after.split('\n').collect();
+
+        for hunk in hunks {
+            let old_text: Vec<char> = old_lines[hunk.old.clone()].join("\n").chars().collect();
+
+            for (start, end, inserted) in hunk_edits(&old_lines[hunk.old.clone()], &new_lines[hunk.new.clone()]) {
+                let line_start = old_text[..start].iter().rposition(|&c| c == '\n').map_or(0, |p| p + 1);
+                let line_offset = old_text[..start].iter().filter(|&&c| c == '\n').count();
+
+                let prefix_start = start.saturating_sub(CONTEXT).max(line_start);
+                let suffix_end = old_text[end..]
+                    .iter()
+                    .take(CONTEXT)
+                    .position(|&c| c == '\n')
+                    .map_or((end + CONTEXT).min(old_text.len()), |p| end + p);
+
+                self.changes.push(Change {
+                    file: file.to_string(),
+                    line: line_map.source_line(hunk.old.start + line_offset),
+                    column: line_map.source_column(hunk.old.start + line_offset, start - line_start + 1),
+                    rule: rule.to_string(),
+                    prefix: old_text[prefix_start..start].iter().collect(),
+                    removed: old_text[start..end].iter().collect(),
+                    inserted,
+                    suffix: old_text[end..suffix_end].iter().collect(),
+                });
+            }
+        }
+    }
+
+    // Write the report next to the EPUB, or wherever the book asks
+    pub fn write_report(&self, config: &AuditConfig, dest_path: &str) {
+        let (extension, content) = match config.format {
+            AuditFormat::Html => ("html", self.to_html()),
+            AuditFormat::Json => ("json", self.to_json()),
+        };
+
+        let path = match &config.path {
+            Some(path) => path.clone(),
+            None => format!("{}-audit.{}", dest_path, extension),
+        };
+
+        create_file(Path::new(&path), content);
+        diagnostics::info(&format!("Audit report with {} change(s) written to {}", self.changes.len(), path));
+    }
+
+    fn to_json(&self) -> String {
+        let changes: Vec<serde_json::Value> = self
+            .changes
+            .iter()
+            .map(|change| {
+                json!({
+                    "file": change.file,
+                    "line": change.line,
+                    "column": change.column,
+                    "rule": change.rule,
+                    "removed": change.removed,
+                    "inserted": change.inserted,
+                    "before": format!("{}{}{}", change.prefix, change.removed, change.suffix),
+                    "after": format!("{}{}{}", change.prefix, change.inserted, change.suffix),
+                })
+            })
+            .collect();
+
+        serde_json::to_string_pretty(&json!({ "changes": changes })).unwrap()
+    }
+
+    fn to_html(&self) -> String {
+        let mut counts: Vec<(&str, usize)> = Vec::new();
+        for change in &self.changes {
+            match counts.iter_mut().find(|(rule, _)| *rule == change.rule) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((&change.rule, 1)),
+            }
+        }
+
+        let mut output = String::from(
+            r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8" />
+<title>Typography changes</title>
+<style>
+body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; width: 100%; }
+th, td { border-bottom: 1px solid #ddd; padding: 0.3em 0.6em; text-align: left; vertical-align: top; }
+td.text { font-family: serif; font-size: 1.1em; white-space: pre-wrap; }
+del { background: #fdd; }
+ins { background: #dfd; text-decoration: none; }
+</style>
+</head>
+<body>
+<h1>Typography changes</h1>
+"#,
+        );
+
+        output.push_str("<ul>\n");
+        for (rule, count) in &counts {
+            output.push_str(&format!("<li>{}: {}</li>\n", escape_html(rule), count));
+        }
+        output.push_str(&format!("</ul>\n<p>{} change(s) in total.</p>\n", self.changes.len()));
+
+        output.push_str("<table>\n<tr><th>File</th><th>Line</th><th>Column</th><th>Rule</th><th>Change</th></tr>\n");
+        for change in &self.changes {
+            output.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td class=\"text\">{}<del>{}</del><ins>{}</ins>{}</td></tr>\n",
+                escape_html(&change.file),
+                change.line,
+                change.column,
+                escape_html(&change.rule),
+                escape_html(&change.prefix),
+                escape_html(&show_breaks(&change.removed)),
+                escape_html(&show_breaks(&change.inserted)),
+                escape_html(&change.suffix)
+            ));
+        }
+        output.push_str("</table>\n</body>\n</html>\n");
+
+        output
+    }
+}
+
+// The edits that turn the old lines of a hunk into the new ones, with
+// ranges in the old lines joined by newlines. A long hunk is diffed line by
+// line when it has as many lines as before, as when a rule changed every
+// line of a passage, and otherwise shown as one edit.
+fn hunk_edits(old_lines: &[&str], new_lines: &[&str]) -> Vec<(usize, usize, String)> {
+    let old_text: Vec<char> = old_lines.join("\n").chars().collect();
+    let new_text: Vec<char> = new_lines.join("\n").chars().collect();
+    if old_text.len() + new_text.len() <= MAX_HUNK {
+        return edits(&old_text, &new_text);
+    }
+    if old_lines.len() != new_lines.len() {
+        return vec![(0, old_text.len(), new_text.into_iter().collect())];
+    }
+
+    let mut found = Vec::new();
+    let mut line_start = 0;
+    for (old_line, new_line) in old_lines.iter().zip(new_lines) {
+        let old_chars: Vec<char> = old_line.chars().collect();
+        let new_chars: Vec<char> = new_line.chars().collect();
+        for (start, end, inserted) in edits(&old_chars, &new_chars) {
+            found.push((line_start + start, line_start + end, inserted));
+        }
+        line_start += old_chars.len() + 1;
+    }
+    found
+}
+
+// Group a character diff into edits: the replaced range of the old text and
+// the text put in its place. Text that was mostly rewritten, such as a break
+// marker turned into markup, is shown as one edit.
+fn edits(old: &[char], new: &[char]) -> Vec<(usize, usize, String)> {
+    let ops = diff(old, new);
+    let kept = ops.iter().filter(|&&op| op == Op::Equal).count();
+    if kept * 2 < old.len().max(new.len()) {
+        return vec![(0, old.len(), new.iter().collect())];
+    }
+
+    let mut edits: Vec<(usize, usize, String)> = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    let mut current: Option<(usize, String)> = None;
+
+    for op in ops {
+        match op {
+            Op::Equal => {
+                if let Some((start, inserted)) = current.take() {
+                    edits.push((start, i, inserted));
+                }
+                i += 1;
+                j += 1;
+            }
+            Op::Delete => {
+                current.get_or_insert_with(|| (i, String::new()));
+                i += 1;
+            }
+            Op::Insert => {
+                current.get_or_insert_with(|| (i, String::new())).1.push(new[j]);
+                j += 1;
+            }
+        }
+    }
+    if let Some((start, inserted)) = current {
+        edits.push((start, i, inserted));
+    }
+
+    // a mark moved past a character or two reads better as one edit
+    let mut merged: Vec<(usize, usize, String)> = Vec::new();
+    for (start, end, inserted) in edits {
+        match merged.last_mut() {
+            Some(last) if start - last.1 <= MERGE_GAP => {
+                last.2.extend(&old[last.1..start]);
+                last.2.push_str(&inserted);
+                last.1 = end;
+            }
+            _ => merged.push((start, end, inserted)),
+        }
+    }
+
+    merged
+}
+
+// Make removed or added line breaks visible in the report
+fn show_breaks(text: &str) -> String {
+    text.replace('\n', "↵\n")
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::line_hunks;
+
+    fn chars(text: &str) -> Vec<char> {
+        text.chars().collect()
+    }
+
+    #[test]
+    fn nearby_edits_are_merged() {
+        // the quote moves past the period: one edit, not two
+        assert_eq!(edits(&chars("said \"no\". Then"), &chars("said \"no.\" Then")), [(8, 10, String::from(".\""))]);
+        assert_eq!(
+            edits(&chars("a -- b and c -- d"), &chars("a – b and c – d")),
+            [(2, 4, String::from("–")), (13, 15, String::from("–"))]
+        );
+        // mostly rewritten text is one edit
+        assert_eq!(edits(&chars("***"), &chars("<hr />")), [(0, 3, String::from("<hr />"))]);
+    }
+
+    #[test]
+    fn long_hunks_are_diffed_line_by_line() {
+        let old: Vec<String> = (0..1500).map(|i| format!("\"Line {}\"", i)).collect();
+        let new: Vec<String> = (0..1500).map(|i| format!("“Line {}”", i)).collect();
+        let old_lines: Vec<&str> = old.iter().map(String::as_str).collect();
+        let new_lines: Vec<&str> = new.iter().map(String::as_str).collect();
+
+        let found = hunk_edits(&old_lines, &new_lines);
+        assert_eq!(found.len(), 3000);
+        // ranges are in the old lines joined together
+        assert_eq!(found[2], (9, 10, String::from("“")));
+        assert_eq!(found[3], (16, 17, String::from("”")));
+
+        // with lines added or removed the hunk is one edit
+        let found = hunk_edits(&old_lines, &new_lines[1..]);
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].0, found[0].1), (0, old_lines.join("\n").chars().count()));
+    }
+
+    #[test]
+    fn changes_are_placed_on_source_lines() {
+        let original = "# One\n***\nShe said -- no.";
+        let mut line_map = LineMap::new(original);
+        let mut audit = Audit::default();
+
+        let broken = "# One\n\n<hr />\n\nShe said -- no.";
+        let hunks = line_hunks(original, broken);
+        audit.record("a.md", "breaks", original, broken, &line_map, &hunks);
+        line_map.apply(original, broken, &hunks);
+
+        let dashed = "# One\n\n<hr />\n\nShe said – no.";
+        audit.record("a.md", "dashes", broken, dashed, &line_map, &line_hunks(broken, dashed));
+
+        let found: Vec<(&str, usize, usize, &str, &str)> = audit
+            .changes
+            .iter()
+            .map(|change| (change.rule.as_str(), change.line, change.column, change.removed.as_str(), change.inserted.as_str()))
+            .collect();
+        assert_eq!(found, [("breaks", 2, 1, "***", "\n<hr />\n"), ("dashes", 3, 10, "--", "–")]);
+        assert_eq!(audit.changes[1].prefix, "She said ");
+        assert_eq!(audit.changes[1].suffix, " no.");
+    }
+}
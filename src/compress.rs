@@ -4,21 +4,26 @@ use std::path::Path;
 use zip::write::{FileOptions, ZipWriter};
 use zip::CompressionMethod;
 
-fn add_dir_to_zip<P: AsRef<Path>>(dir_path: &P, prefix: &str, zip: &mut ZipWriter<File>) -> zip::result::ZipResult<()> {
+use crate::diagnostics::Diagnostic;
+
+// Files listed in `stored` (obfuscated fonts) are stored without compression
+fn add_dir_to_zip<P: AsRef<Path>>(dir_path: &P, prefix: &str, stored: &[String], zip: &mut ZipWriter<File>) -> zip::result::ZipResult<()> {
     for entry in read_dir(dir_path)? {
         let entry = entry?;
         let path = entry.path();
-        let name = path.file_name().unwrap().to_str().unwrap();
+        let name = entry.file_name().to_string_lossy().to_string();
 
         if path.is_dir() {
-            add_dir_to_zip(&path, &format!("{}/{}", prefix, name), zip)?;
+            add_dir_to_zip(&path, &format!("{}/{}", prefix, name), stored, zip)?;
         } else {
             let mut file = File::open(&path)?;
+            let zip_path = format!("{}/{}", prefix, name);
+            let method = if stored.contains(&zip_path) { CompressionMethod::Stored } else { CompressionMethod::Deflated };
             let options = FileOptions::default()
-                .compression_method(CompressionMethod::Deflated)
+                .compression_method(method)
                 .unix_permissions(0o755);
 
-            zip.start_file(format!("{}/{}", prefix, name), options)?;
+            zip.start_file(zip_path, options)?;
             let mut buffer = Vec::new();
             file.read_to_end(&mut buffer)?;
             zip.write_all(&buffer)?;
@@ -28,13 +33,13 @@ fn add_dir_to_zip<P: AsRef<Path>>(dir_path: &P, prefix: &str, zip: &mut ZipWrite
     Ok(())
 }
 
-pub fn compress_epub(folder_path: &str) {
+pub fn compress_epub(folder_path: &str, stored: &[String]) {
     let file_name = format!("{}.epub", folder_path);
     let path = Path::new(&file_name);
-    let file = match File::create(&path) {
+    let file = match File::create(path) {
         Ok(file) => file,
         Err(e) => {
-            println!("Failed to create the file {}: {}", file_name, e);
+            Diagnostic::error(format!("failed to create the file {}: {}", file_name, e)).emit();
             return;
         },
     };
@@ -45,7 +50,7 @@ pub fn compress_epub(folder_path: &str) {
     let mut mimetype_file = match File::open(format!("{}/mimetype", folder_path)) {
         Ok(file) => file,
         Err(e) => {
-            println!("Failed to open the mimetype file: {}", e);
+            Diagnostic::error(format!("failed to open the mimetype file: {}", e)).emit();
             return;
         },
     };
@@ -55,31 +60,31 @@ pub fn compress_epub(folder_path: &str) {
         .unix_permissions(0o755);
 
     if let Err(e) = zip.start_file("mimetype", options) {
-        println!("Failed to start the mimetype file: {}", e);
+        Diagnostic::error(format!("failed to start the mimetype file: {}", e)).emit();
         return;
     }
 
     let mut buffer = Vec::new();
     if let Err(e) = mimetype_file.read_to_end(&mut buffer) {
-        println!("Failed to read the mimetype file: {}", e);
+        Diagnostic::error(format!("failed to read the mimetype file: {}", e)).emit();
         return;
     };
 
     if let Err(e) = zip.write_all(&buffer) {
-        println!("Failed to write the mimetype file: {}", e);
+        Diagnostic::error(format!("failed to write the mimetype file: {}", e)).emit();
         return;
     }
 
     // Add the contents of the META-INF and OPS directories.
     for dir in ["META-INF", "OPS"].iter() {
         let dir_path = format!("{}/{}", folder_path, dir);
-        if let Err(e) = add_dir_to_zip(&dir_path, dir, &mut zip) {
-            println!("Failed to add directory {}: {}", dir, e);
+        if let Err(e) = add_dir_to_zip(&dir_path, dir, stored, &mut zip) {
+            Diagnostic::error(format!("failed to add directory {}: {}", dir, e)).emit();
             return;
         }
     }
 
     if let Err(e) = zip.finish() {
-        println!("Failed to finish the zip: {}", e);
+        Diagnostic::error(format!("failed to finish the zip: {}", e)).emit();
     }
 }
@@ -0,0 +1,184 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+use serde_json::json;
+
+// How warnings and errors are printed, chosen with --message-format
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageFormat {
+    // compiler style on stderr
+    Human,
+    // one JSON object per line on stdout, for editors
+    Json,
+}
+
+static FORMAT: OnceLock<MessageFormat> = OnceLock::new();
+// the book folder, which file names in diagnostics are relative to
+static ROOT: OnceLock<PathBuf> = OnceLock::new();
+static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+static ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn init(format: MessageFormat, book_folder: &str) {
+    let _ = FORMAT.set(format);
+    let _ = ROOT.set(PathBuf::from(book_folder));
+}
+
+pub fn format() -> MessageFormat {
+    FORMAT.get().copied().unwrap_or(MessageFormat::Human)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+pub struct Diagnostic {
+    level: Level,
+    message: String,
+    file: Option<String>,
+    // 1-based, in the file as the author wrote it
+    line: Option<usize>,
+    column: Option<usize>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>) -> Diagnostic {
+        Diagnostic { level: Level::Error, message: message.into(), file: None, line: None, column: None }
+    }
+
+    pub fn warning(message: impl Into<String>) -> Diagnostic {
+        Diagnostic { level: Level::Warning, message: message.into(), file: None, line: None, column: None }
+    }
+
+    pub fn in_file(mut self, file: &str) -> Diagnostic {
+        self.file = Some(file.to_string());
+        self
+    }
+
+    pub fn at(mut self, file: &str, line: usize, column: usize) -> Diagnostic {
+        self.file = Some(file.to_string());
+        self.line = Some(line);
+        self.column = Some(column);
+        self
+    }
+
+    // Point at the first place `needle` appears in book.yaml, or at the
+    // file as a whole
+    pub fn in_config(self, needle: &str) -> Diagnostic {
+        self.near("book.yaml", needle)
+    }
+
+    // Point at the first place `needle` appears in `file`
+    pub fn near(self, file: &str, needle: &str) -> Diagnostic {
+        let found = read_source(file).and_then(|text| {
+            text.lines().enumerate().find_map(|(i, line)| {
+                line.find(needle).map(|byte| (i + 1, line[..byte].chars().count() + 1))
+            })
+        });
+
+        match found {
+            Some((line, column)) => self.at(file, line, column),
+            None => self.in_file(file),
+        }
+    }
+
+    pub fn emit(self) {
+        match self.level {
+            Level::Error => ERRORS.fetch_add(1, Ordering::Relaxed),
+            Level::Warning => WARNINGS.fetch_add(1, Ordering::Relaxed),
+        };
+
+        let snippet = match (&self.file, self.line) {
+            (Some(file), Some(line)) => {
+                read_source(file).and_then(|text| text.lines().nth(line - 1).map(String::from))
+            }
+            _ => None,
+        };
+
+        match format() {
+            MessageFormat::Json => println!("{}", self.to_json(snippet)),
+            MessageFormat::Human => eprintln!("{}", self.to_human(snippet)),
+        }
+    }
+
+    fn level_name(&self) -> &'static str {
+        match self.level {
+            Level::Error => "error",
+            Level::Warning => "warning",
+        }
+    }
+
+    fn to_json(&self, snippet: Option<String>) -> String {
+        json!({
+            "level": self.level_name(),
+            "message": self.message,
+            "file": self.file,
+            "line": self.line,
+            "column": self.column,
+            "snippet": snippet,
+        })
+        .to_string()
+    }
+
+    //     warning: unknown preprocess rule "smart"
+    //      --> book.yaml:9:7
+    //       |
+    //     9 |     - smart
+    //       |       ^
+    fn to_human(&self, snippet: Option<String>) -> String {
+        let mut output = format!("{}: {}", self.level_name(), self.message);
+
+        let Some(file) = &self.file else {
+            return output;
+        };
+
+        let (Some(line), Some(snippet)) = (self.line, snippet) else {
+            output.push_str(&format!("\n --> {}", file));
+            return output;
+        };
+
+        let column = self.column.unwrap_or(1);
+        let gutter = " ".repeat(line.to_string().len());
+        // keep the caret under the right character when the line has tabs
+        let indent: String = snippet
+            .chars()
+            .take(column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        output.push_str(&format!("\n{}--> {}:{}:{}", gutter, file, line, column));
+        output.push_str(&format!("\n{} |", gutter));
+        output.push_str(&format!("\n{} | {}", line, snippet));
+        output.push_str(&format!("\n{} | {}^", gutter, indent));
+        output
+    }
+}
+
+// Progress messages go to stdout, unless stdout is taken by JSON diagnostics
+pub fn info(message: &str) {
+    match format() {
+        MessageFormat::Human => println!("{}", message),
+        MessageFormat::Json => eprintln!("{}", message),
+    }
+}
+
+pub fn error_count() -> usize {
+    ERRORS.load(Ordering::Relaxed)
+}
+
+// Print how many warnings and errors were reported, if any
+pub fn print_summary() {
+    let warnings = WARNINGS.load(Ordering::Relaxed);
+    let errors = error_count();
+    if format() == MessageFormat::Human && warnings + errors > 0 {
+        eprintln!("{} warning(s), {} error(s)", warnings, errors);
+    }
+}
+
+fn read_source(file: &str) -> Option<String> {
+    let root = ROOT.get()?;
+    fs::read_to_string(root.join(file)).ok()
+}
@@ -0,0 +1,436 @@
+use std::ops::Range;
+
+// Shortest edit script between two sequences, by Myers' O(ND) algorithm in
+// its linear space form: find the middle snake of an optimal path, then
+// solve the parts before and after it the same way
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    // the next item of both sequences
+    Equal,
+    // the next item of the old sequence only
+    Delete,
+    // the next item of the new sequence only
+    Insert,
+}
+
+pub fn diff<T: PartialEq>(old: &[T], new: &[T]) -> Vec<Op> {
+    let mut ops = Vec::with_capacity(old.len().max(new.len()));
+    solve(old, new, &mut ops);
+    ops
+}
+
+fn solve<T: PartialEq>(old: &[T], new: &[T], ops: &mut Vec<Op>) {
+    // common ends are cheap to strip and keep the search small
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (middle_old, middle_new) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+
+    ops.extend(std::iter::repeat_n(Op::Equal, prefix));
+    if middle_old.is_empty() || middle_new.is_empty() {
+        ops.extend(std::iter::repeat_n(Op::Delete, middle_old.len()));
+        ops.extend(std::iter::repeat_n(Op::Insert, middle_new.len()));
+    } else {
+        // with the ends stripped there are at least two edits, so both
+        // sides of the snake are smaller than the whole
+        let snake = middle_snake(middle_old, middle_new);
+        solve(&middle_old[..snake.start.0], &middle_new[..snake.start.1], ops);
+        ops.extend(std::iter::repeat_n(Op::Equal, snake.end.0 - snake.start.0));
+        solve(&middle_old[snake.end.0..], &middle_new[snake.end.1..], ops);
+    }
+    ops.extend(std::iter::repeat_n(Op::Equal, suffix));
+}
+
+// A run of equal items on an optimal path, from `start` to `end` as
+// positions in the old and new sequences
+struct Snake {
+    start: (usize, usize),
+    end: (usize, usize),
+}
+
+// Search from both ends at once, keeping only the furthest point reached on
+// each diagonal, until the two searches meet
+fn middle_snake<T: PartialEq>(old: &[T], new: &[T]) -> Snake {
+    let (n, m) = (old.len() as isize, new.len() as isize);
+    let delta = n - m;
+    let odd = delta % 2 != 0;
+    let max = (n + m + 1) / 2;
+    let offset = max + 1;
+
+    // furthest x on each diagonal k = x - y going forwards, and furthest
+    // distance from the end on each diagonal of the reversed sequences
+    let mut forward = vec![0isize; 2 * max as usize + 3];
+    let mut backward = vec![0isize; 2 * max as usize + 3];
+
+    for d in 0..=max {
+        let mut k = -d;
+        while k <= d {
+            let index = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && forward[index - 1] < forward[index + 1]) {
+                forward[index + 1]
+            } else {
+                forward[index - 1] + 1
+            };
+            let mut y = x - k;
+            let start = (x as usize, y as usize);
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            forward[index] = x;
+
+            // the backward search of the step before reached this diagonal
+            let reverse_k = delta - k;
+            if odd && reverse_k.abs() < d && x + backward[(reverse_k + offset) as usize] >= n {
+                return Snake { start, end: (x as usize, y as usize) };
+            }
+            k += 2;
+        }
+
+        let mut k = -d;
+        while k <= d {
+            let index = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && backward[index - 1] < backward[index + 1]) {
+                backward[index + 1]
+            } else {
+                backward[index - 1] + 1
+            };
+            let mut y = x - k;
+            let end = ((n - x) as usize, (m - y) as usize);
+            while x < n && y < m && old[(n - x - 1) as usize] == new[(m - y - 1) as usize] {
+                x += 1;
+                y += 1;
+            }
+            backward[index] = x;
+
+            let forward_k = delta - k;
+            if !odd && forward_k.abs() <= d && forward[(forward_k + offset) as usize] + x >= n {
+                return Snake { start: ((n - x) as usize, (m - y) as usize), end };
+            }
+            k += 2;
+        }
+    }
+
+    unreachable!("the searches meet within (n + m) / 2 steps")
+}
+
+// A run of changed lines: these old lines were replaced by those new ones
+pub struct Hunk {
+    pub old: Range<usize>,
+    pub new: Range<usize>,
+}
+
+pub fn line_hunks(before: &str, after: &str) -> Vec<Hunk> {
+    let old_lines: Vec<&str> = before.split('\n').collect();
+    let new_lines: Vec<&str> = after.split('\n').collect();
+
+    let mut hunks = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    let mut start: Option<(usize, usize)> = None;
+
+    for op in diff(&old_lines, &new_lines) {
+        match op {
+            Op::Equal => {
+                if let Some((i0, j0)) = start.take() {
+                    hunks.push(Hunk { old: i0..i, new: j0..j });
+                }
+                i += 1;
+                j += 1;
+            }
+            Op::Delete => {
+                start.get_or_insert((i, j));
+                i += 1;
+            }
+            Op::Insert => {
+                start.get_or_insert((i, j));
+                j += 1;
+            }
+        }
+    }
+    if let Some((i0, j0)) = start {
+        hunks.push(Hunk { old: i0..i, new: j0..j });
+    }
+
+    hunks
+}
+
+// For each line of a text being rewritten, the line of the original file it
+// came from and where its characters were, so positions can still be
+// reported against what the author wrote
+#[derive(Clone)]
+pub struct LineMap(Vec<Line>);
+
+#[derive(Clone)]
+struct Line {
+    // 1-based line in the original file
+    source: usize,
+    // 0-based column in the original line of each character, or None while
+    // the line is as the author wrote it
+    columns: Option<Vec<usize>>,
+}
+
+impl Line {
+    fn source_column(&self, index: usize) -> usize {
+        match &self.columns {
+            None => index,
+            Some(columns) => match columns.get(index) {
+                Some(&column) => column,
+                // past the end, so after the last character
+                None => columns.last().map_or(0, |&column| column + 1),
+            },
+        }
+    }
+}
+
+impl LineMap {
+    pub fn new(text: &str) -> LineMap {
+        LineMap((1..=text.split('\n').count()).map(|source| Line { source, columns: None }).collect())
+    }
+
+    fn line(&self, index: usize) -> Option<&Line> {
+        self.0.get(index).or(self.0.last())
+    }
+
+    // 1-based source line of the 0-based line `index` of the current text
+    pub fn source_line(&self, index: usize) -> usize {
+        self.line(index).map_or(1, |line| line.source)
+    }
+
+    // 1-based column in the source line of the 1-based `column` of the
+    // 0-based line `index` of the current text
+    pub fn source_column(&self, index: usize, column: usize) -> usize {
+        self.line(index).map_or(column, |line| line.source_column(column.saturating_sub(1)) + 1)
+    }
+
+    // Follow an edit of the text from `before` to `after`. Unchanged lines
+    // keep their source line; changed ones take the source line of the old
+    // line at the same place in their hunk, and their characters are matched
+    // against it to keep track of columns.
+    pub fn apply(&mut self, before: &str, after: &str, hunks: &[Hunk]) {
+        let old_lines: Vec<&str> = before.split('\n').collect();
+        let new_lines: Vec<&str> = after.split('\n').collect();
+        let mut map = Vec::with_capacity(new_lines.len());
+        let mut i = 0;
+
+        for hunk in hunks {
+            map.extend_from_slice(&self.0[i..hunk.old.start]);
+            for k in 0..hunk.new.len() {
+                let new_line = new_lines[hunk.new.start + k];
+
+                // a line put in between others has nothing to match
+                if hunk.old.is_empty() {
+                    let source = self.source_line(hunk.old.start);
+                    map.push(Line { source, columns: Some(vec![0; new_line.chars().count()]) });
+                    continue;
+                }
+
+                let old_index = hunk.old.start + k.min(hunk.old.len() - 1);
+                let old_line = &self.0[old_index];
+                let old_chars: Vec<char> = old_lines[old_index].chars().collect();
+                let new_chars: Vec<char> = new_line.chars().collect();
+
+                // each new character is placed where it was; inserted ones
+                // where the text they replace starts
+                let mut columns = Vec::with_capacity(new_chars.len());
+                let mut position = 0;
+                let mut change_start = None;
+                for op in diff(&old_chars, &new_chars) {
+                    match op {
+                        Op::Equal => {
+                            columns.push(old_line.source_column(position));
+                            position += 1;
+                            change_start = None;
+                        }
+                        Op::Delete => {
+                            change_start.get_or_insert(position);
+                            position += 1;
+                        }
+                        Op::Insert => columns.push(old_line.source_column(*change_start.get_or_insert(position))),
+                    }
+                }
+
+                map.push(Line { source: old_line.source, columns: Some(columns) });
+            }
+            i = hunk.old.end;
+        }
+        map.extend_from_slice(&self.0[i..]);
+
+        self.0 = map;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Replay an edit script, checking it turns `old` into `new`
+    fn replay(old: &[char], new: &[char], ops: &[Op]) -> String {
+        let (mut i, mut j) = (0, 0);
+        let mut output = String::new();
+        for op in ops {
+            match op {
+                Op::Equal => {
+                    assert_eq!(old[i], new[j]);
+                    output.push(old[i]);
+                    i += 1;
+                    j += 1;
+                }
+                Op::Delete => i += 1,
+                Op::Insert => {
+                    output.push(new[j]);
+                    j += 1;
+                }
+            }
+        }
+        assert_eq!((i, j), (old.len(), new.len()));
+        output
+    }
+
+    #[test]
+    fn shortest_edit_script() {
+        let cases = [("abcabba", "cbabac", 5), ("", "abc", 3), ("abc", "", 3), ("same", "same", 0), ("kitten", "sitting", 5)];
+
+        for (old, new, edits) in cases {
+            let (old_chars, new_chars): (Vec<char>, Vec<char>) = (old.chars().collect(), new.chars().collect());
+            let ops = diff(&old_chars, &new_chars);
+            assert_eq!(replay(&old_chars, &new_chars, &ops), new, "{} -> {}", old, new);
+            assert_eq!(ops.iter().filter(|&&op| op != Op::Equal).count(), edits, "{} -> {}", old, new);
+        }
+    }
+
+    // Length of the longest common subsequence, the slow way
+    fn lcs(old: &[u8], new: &[u8]) -> usize {
+        let mut row = vec![0; new.len() + 1];
+        for a in old {
+            let mut diagonal = 0;
+            for (j, b) in new.iter().enumerate() {
+                let above = row[j + 1];
+                row[j + 1] = if a == b { diagonal + 1 } else { row[j + 1].max(row[j]) };
+                diagonal = above;
+            }
+        }
+        row[new.len()]
+    }
+
+    #[test]
+    fn minimal_on_generated_sequences() {
+        // a small linear congruential generator, so the cases are the same
+        // on every run
+        let mut seed = 12345u32;
+        let mut next = move |limit: u32| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) % limit
+        };
+
+        for _ in 0..300 {
+            let old: Vec<u8> = (0..next(30)).map(|_| b'a' + next(4) as u8).collect();
+            let new: Vec<u8> = (0..next(30)).map(|_| b'a' + next(4) as u8).collect();
+            let ops = diff(&old, &new);
+
+            let (mut i, mut j) = (0, 0);
+            for op in &ops {
+                match op {
+                    Op::Equal => {
+                        assert_eq!(old[i], new[j]);
+                        i += 1;
+                        j += 1;
+                    }
+                    Op::Delete => i += 1,
+                    Op::Insert => j += 1,
+                }
+            }
+            assert_eq!((i, j), (old.len(), new.len()));
+            let edits = ops.iter().filter(|&&op| op != Op::Equal).count();
+            assert_eq!(edits, old.len() + new.len() - 2 * lcs(&old, &new));
+        }
+    }
+
+    #[test]
+    fn every_line_changed() {
+        let before: String = (0..3000).map(|i| format!("\"Line {}\"\n", i)).collect();
+        let after: String = (0..3000).map(|i| format!("“Line {}”\n", i)).collect();
+        assert_eq!(hunks(&before, &after), [(0..3000, 0..3000)]);
+    }
+
+    fn hunks(before: &str, after: &str) -> Vec<(Range<usize>, Range<usize>)> {
+        line_hunks(before, after).into_iter().map(|hunk| (hunk.old, hunk.new)).collect()
+    }
+
+    #[test]
+    fn inserted_lines() {
+        assert_eq!(hunks("a\nb\nc", "a\nx\ny\nb\nc"), [(1..1, 1..3)]);
+        assert_eq!(hunks("a\nb", "a\nb\nc"), [(2..2, 2..3)]);
+    }
+
+    #[test]
+    fn deleted_lines() {
+        assert_eq!(hunks("a\nb\nc\nd", "a\nd"), [(1..3, 1..1)]);
+        assert_eq!(hunks("a\nb\nc", "b\nc"), [(0..1, 0..0)]);
+    }
+
+    #[test]
+    fn changed_lines() {
+        assert_eq!(hunks("a\nb\nc\nd", "a\nB\nc\nD"), [(1..2, 1..2), (3..4, 3..4)]);
+        assert_eq!(hunks("a\nb\nc", "a\nb1\nb2\nb3\nc"), [(1..2, 1..4)]);
+        assert!(hunks("a\nb", "a\nb").is_empty());
+    }
+
+    // Apply one pipeline stage to a text and its line map
+    fn stage(text: &mut String, line_map: &mut LineMap, new: &str) {
+        line_map.apply(text, new, &line_hunks(text, new));
+        *text = new.to_string();
+    }
+
+    #[test]
+    fn lines_map_back_through_stages() {
+        let mut text = String::from("# Title\n:::note\nHello\n:::\n***\nBye");
+        let mut line_map = LineMap::new(&text);
+
+        // a block opener becomes three lines
+        stage(&mut text, &mut line_map, "# Title\n\n<aside>\n\nHello\n:::\n***\nBye");
+        // the closer too
+        stage(&mut text, &mut line_map, "# Title\n\n<aside>\n\nHello\n\n</aside>\n\n***\nBye");
+        // the break marker is dropped, and a line is rewritten
+        stage(&mut text, &mut line_map, "# Title\n\n<aside>\n\nHello!\n\n</aside>\n\nBye");
+
+        let lines: Vec<usize> = (0..text.split('\n').count()).map(|i| line_map.source_line(i)).collect();
+        assert_eq!(lines, [1, 2, 2, 2, 3, 4, 4, 4, 6]);
+        // past the end is the last line
+        assert_eq!(line_map.source_line(100), 6);
+    }
+
+    #[test]
+    fn columns_map_back_through_stages() {
+        let original = "\"Wait...\" she said -- see [x](a.md) now.";
+        let mut text = String::from(original);
+        let mut line_map = LineMap::new(&text);
+
+        stage(&mut text, &mut line_map, "“Wait...” she said -- see [x](a.md) now.");
+        stage(&mut text, &mut line_map, "“Wait…” she said -- see [x](a.md) now.");
+        stage(&mut text, &mut line_map, "“Wait…” she said – see [x](a.md) now.");
+
+        let column = |text: &str, needle: &str| text[..text.find(needle).unwrap()].chars().count() + 1;
+        for needle in ["[x]", "see", "now", "said"] {
+            assert_eq!(line_map.source_column(0, column(&text, needle)), column(original, needle), "{}", needle);
+        }
+        // an inserted character takes the place of what it replaced
+        assert_eq!(line_map.source_column(0, column(&text, "–")), column(original, "--"));
+        // past the end of the line
+        assert_eq!(line_map.source_column(0, 100), original.chars().count() + 1);
+    }
+
+    #[test]
+    fn inserted_lines_have_no_columns() {
+        let mut text = String::from("a\nb");
+        let mut line_map = LineMap::new(&text);
+        stage(&mut text, &mut line_map, "a\n<hr />\nb");
+
+        assert_eq!((line_map.source_line(1), line_map.source_column(1, 4)), (2, 1));
+        assert_eq!((line_map.source_line(2), line_map.source_column(2, 1)), (2, 1));
+    }
+}
@@ -1,7 +1,12 @@
-use std::fs::{self, DirEntry};
+use std::fs;
 use std::path::{Path, PathBuf};
 use chrono::prelude::*;
 
+use crate::assets;
+use crate::diagnostics::{self, Diagnostic};
+use crate::media::Kind;
+use crate::fonts::FONTS_CSS;
+use crate::theme;
 use crate::types::*;
 use crate::util::create_file;
 
@@ -11,11 +16,11 @@ pub fn create_toc_xhtml(epub_info: &EpubInfo, pages: &[Page], dest_folder: &str)
 
     // Generate the content of toc.xhtml
     let mut toc_content = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>
-<html xml:lang="en" xmlns:epub="http://www.idpf.org/2007/ops" xmlns="http://www.w3.org/1999/xhtml">
+<html xml:lang="{lang}" lang="{lang}" xmlns:epub="http://www.idpf.org/2007/ops" xmlns="http://www.w3.org/1999/xhtml">
 <head>
     <meta charset="UTF-8" />
     <title>Table of Contents</title>
-    <link rel="stylesheet" href="css/book.css" type="text/css" />
+    <link rel="stylesheet" href="css/builtin.css" type="text/css" />{styles}
     <meta name="EPB-UUID" content="" />
 </head>
 <body>
@@ -36,6 +41,9 @@ pub fn create_toc_xhtml(epub_info: &EpubInfo, pages: &[Page], dest_folder: &str)
         }
     }
 
+    toc_content = toc_content.replace("{lang}", epub_info.language());
+    toc_content = toc_content.replace("{styles}", &stylesheet_links(epub_info, &[], ""));
+
     // Replace the content of the EPB-UUID meta tag
     let epub_uuid = epub_info.id.as_deref().unwrap_or("");
     toc_content = toc_content.replace(r#"meta name="EPB-UUID" content=""#, &format!(r#"meta name="EPB-UUID" content="{}"#, epub_uuid));
@@ -49,25 +57,28 @@ pub fn create_toc_xhtml(epub_info: &EpubInfo, pages: &[Page], dest_folder: &str)
 
     // Write the toc.xhtml content to the destination file
     fs::write(&toc_path, toc_content)
-        .unwrap_or_else(|err| eprintln!("Error writing toc.xhtml file: {}", err));
+        .unwrap_or_else(|err| Diagnostic::error(format!("failed to write toc.xhtml file: {}", err)).emit());
 }
 
 pub fn create_xhtml_files(epub_info: &EpubInfo, pages: &[Page], dest_folder: &str) {
     for page in pages {
         let file_name = format!("{}.xhtml", page.file);
         let file_path = Path::new(dest_folder).join("OPS/content").join(&file_name);
+        if let Some(parent) = file_path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
         
         // Use page.title if it is not empty, else use epub_info.title
         let title = if !page.title.trim().is_empty() { &page.title } else { &epub_info.title };
 
         let xhtml_content = format!(
             r#"<!DOCTYPE html>
-<html xmlns="http://www.w3.org/1999/xhtml">
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops" xml:lang="{lang}" lang="{lang}">
 <head>
     <title>{}</title>
     <meta name="EPB-UUID" content="{}" />
     <meta charset="UTF-8" />
-    <link rel="stylesheet" href="../css/book.css" type="text/css" />
+    <link rel="stylesheet" href="../css/builtin.css" type="text/css" />{styles}
 </head>
 <body>
     {}
@@ -76,46 +87,79 @@ pub fn create_xhtml_files(epub_info: &EpubInfo, pages: &[Page], dest_folder: &st
 "#,
             title,
             epub_info.id.as_ref().unwrap_or(&"".to_string()),
-            page.body
+            page.body,
+            lang = epub_info.language(),
+            styles = stylesheet_links(epub_info, &page.styles, "../")
         );
 
         fs::write(&file_path, xhtml_content)
-            .unwrap_or_else(|err| eprintln!("Error writing XHTML file: {}", err));
+            .unwrap_or_else(|err| Diagnostic::error(format!("failed to write XHTML file: {}", err)).emit());
+    }
+}
+
+// A <link> for each stylesheet after the built-in one: the font faces, the
+// theme, then the book's, then the page's
+fn stylesheet_links(epub_info: &EpubInfo, page_styles: &[String], prefix: &str) -> String {
+    let fonts_css = Some(FONTS_CSS.to_string()).filter(|_| !epub_info.font_faces.is_empty());
+
+    fonts_css
+        .iter()
+        .chain(&theme::href(epub_info))
+        .chain(&epub_info.stylesheets)
+        .chain(page_styles)
+        .map(|href| format!("\n    <link rel=\"stylesheet\" href=\"{}{}\" type=\"text/css\" />", prefix, escape_href(href)))
+        .collect()
+}
+
+// The stylesheets mkepub writes itself, inside OPS
+pub fn generated_stylesheets(epub_info: &EpubInfo) -> Vec<String> {
+    let mut hrefs = vec![String::from("css/builtin.css")];
+    if !epub_info.font_faces.is_empty() {
+        hrefs.push(FONTS_CSS.to_string());
     }
+    hrefs.extend(theme::href(epub_info));
+    hrefs
 }
 
-pub fn create_epub(dest_path: &Path, epub_info: &EpubInfo, pages: &Vec<Page>) {
-    // Create the destination folder if it doesn't exist
-    // if let Some(parent_dir) = dest_path.parent() {
-    //     fs::create_dir_all(parent_dir).expect("Failed to create destination folder");
-    // }
+// Fallback styles for the markup mkepub generates (chat blocks and the like)
+pub fn create_builtin_css(dest_folder: &str) {
+    let css_path = Path::new(dest_folder).join("OPS/css/builtin.css");
+    if let Some(parent) = css_path.parent() {
+        let _ = fs::create_dir_all(parent);
+    }
 
-    fs::create_dir_all(dest_path).expect("Failed to create destination folder");
+    fs::write(&css_path, include_str!("css/builtin.css"))
+        .unwrap_or_else(|err| Diagnostic::error(format!("failed to write builtin.css file: {}", err)).emit());
+}
 
+pub fn create_epub(dest_path: &Path, epub_info: &EpubInfo, pages: &[Page]) {
     // Create the necessary subdirectories within the EPUB structure
     let epub_folders = vec!["META-INF", "OPS", "OPS/content"]; //
     for folder in &epub_folders {
         let folder_path = dest_path.join(folder);
-        fs::create_dir(&folder_path).expect("Failed to create folder");
+        if let Err(err) = fs::create_dir_all(&folder_path) {
+            Diagnostic::error(format!("failed to create {}: {}", folder_path.display(), err)).emit();
+            return;
+        }
 
         // Create the core skeleton files in the appropriate folders
         match *folder {
             "META-INF" => {
                 create_file(&folder_path.join("container.xml"), create_container_xml_content());
                 
-                if let Some(_) = epub_info.fonts {
+                if epub_info.assets.iter().any(|asset| asset.media_type.kind == Kind::Font) {
                     create_file(&folder_path.join("com.apple.ibooks.display-options.xml"), create_apple_xml_meta());
                 }
             }
             "OPS" => {
-                create_file(&folder_path.join("epb.opf"), create_content_opf_content(epub_info, &pages));
-                create_file(&folder_path.join("epb.ncx"), create_toc_ncx_content(epub_info, &pages));
+                create_file(&folder_path.join("epb.opf"), create_content_opf_content(epub_info, pages));
+                create_file(&folder_path.join("epb.ncx"), create_toc_ncx_content(epub_info, pages));
             }
             _ => {}
         }
     }
 
-    println!("Uncompressed skeleton EPUB structure created at: {:?}", dest_path);
+    diagnostics::info(&format!("Uncompressed skeleton EPUB structure created at: {:?}", dest_path));
 }
 
 fn create_content_opf_content(epub_info: &EpubInfo, pages: &[Page]) -> String {
@@ -140,44 +184,50 @@ fn create_content_opf_content(epub_info: &EpubInfo, pages: &[Page]) -> String {
     let spine_items = pages
         .iter()
         .enumerate()
-        .map(|(index, page)| format!("<itemref idref=\"item-{}\" />", index + 1))
+        .map(|(index, _)| format!("<itemref idref=\"item-{}\" />", index + 1))
         .collect::<Vec<String>>()
         .join("\n");
 
     let modified = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
 
-    // create a manifest entry for each font file
-    let font_items = match &epub_info.fonts {
-        Some(fonts) => fonts
-            .iter()
-            .enumerate()
-            .map(|(index, font)| {
-                format!(
-                    r#"<item href="fonts/{}" id="font{}" media-type="application/x-font-otf"/>"#,
-                    font, index + 1
-                )
-            })
-            .collect::<Vec<String>>()
-            .join("\n"),
-        None => String::new(),
-    };
+    // create a manifest entry for each image, font, stylesheet and so on
+    let asset_items = epub_info
+        .assets
+        .iter()
+        .map(|asset| {
+            let mut attributes = match &asset.fallback {
+                Some(id) => format!(r#" fallback="{}""#, id),
+                None => String::new(),
+            };
+            if assets::cover(&epub_info.assets).is_some_and(|cover| cover.id == asset.id) {
+                attributes.push_str(r#" properties="cover-image""#);
+            }
+            format!(
+                r#"<item id="{}" href="{}" media-type="{}"{}/>"#,
+                asset.id,
+                escape_href(&asset.href),
+                asset.media_type.mime,
+                attributes
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n    ");
 
-    // create a manifest entry for each image
-    let image_items = match &epub_info.images {
-        Some(images) => images
-            .iter()
-            .map(|image| {
-                let image_id = image.split('.').next().unwrap_or_default();
-                format!(
-                    r#"<item id="{}" href="images/{}" media-type="image/jpeg"/>"#,
-                    image_id, image
-                )
-            })
-            .collect::<Vec<String>>()
-            .join("\n"),
+    // EPUB 2 reading systems find the cover through a meta naming its id
+    let cover_meta = match assets::cover(&epub_info.assets) {
+        Some(cover) => format!("\n    <meta name=\"cover\" content=\"{}\"/>", cover.id),
         None => String::new(),
     };
 
+    // the stylesheets mkepub generates besides the built-in one
+    let mut stylesheet_items: Vec<String> = Vec::new();
+    if !epub_info.font_faces.is_empty() {
+        stylesheet_items.push(format!(r#"<item id="fonts-stylesheet" href="{}" media-type="text/css"/>"#, FONTS_CSS));
+    }
+    if let Some(href) = theme::href(epub_info) {
+        stylesheet_items.push(format!(r#"<item id="theme-stylesheet" href="{}" media-type="text/css"/>"#, href));
+    }
+
     format!(
         r#"<?xml version="1.0" encoding="UTF-8"?>
 <package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="BookID">
@@ -185,14 +235,14 @@ fn create_content_opf_content(epub_info: &EpubInfo, pages: &[Page]) -> String {
     <dc:identifier id="BookID">{}</dc:identifier>
     <dc:title>{}</dc:title>
     <dc:creator>{}</dc:creator>
-    <dc:language>en</dc:language>
-    <meta property="dcterms:modified">{}</meta>
+    <dc:language>{}</dc:language>
+    <meta property="dcterms:modified">{}</meta>{}
   </metadata>
   <manifest>
     <item id="toc" href="toc.xhtml" media-type="application/xhtml+xml" properties="nav"/>
     {}
     <item id="ncx" href="epb.ncx" media-type="application/x-dtbncx+xml"/>
-    <item id="stylesheet" href="css/book.css" media-type="text/css"/>
+    <item id="builtin-stylesheet" href="css/builtin.css" media-type="text/css"/>
     {}
     {}
   </manifest>
@@ -204,10 +254,12 @@ fn create_content_opf_content(epub_info: &EpubInfo, pages: &[Page]) -> String {
         book_id,
         epub_info.title,
         epub_info.author,
+        epub_info.language(),
         modified,
+        cover_meta,
         manifest_items,
-        font_items,
-        image_items,
+        stylesheet_items.join("\n    "),
+        asset_items,
         spine_items
     )
 }
@@ -285,3 +337,49 @@ fn create_toc_ncx_content(epub_info: &EpubInfo, pages: &[Page]) -> String {
         nav_map
     )
 }
+
+// An href as it goes in an XML attribute, with the characters a URL may not
+// hold percent-encoded
+pub fn escape_href(href: &str) -> String {
+    href.chars()
+        .map(|c| match c {
+            ' ' => String::from("%20"),
+            '"' => String::from("%22"),
+            '%' => String::from("%25"),
+            '&' => String::from("&amp;"),
+            '<' => String::from("&lt;"),
+            '>' => String::from("&gt;"),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media;
+
+    fn image(source: &str, id: &str) -> Asset {
+        Asset {
+            source: source.to_string(),
+            href: format!("images/{}", source),
+            id: id.to_string(),
+            media_type: media::from_extension(Path::new(source)).unwrap(),
+            fallback: None,
+        }
+    }
+
+    #[test]
+    fn cover_meta_names_the_cover_item() {
+        let mut epub_info: EpubInfo = serde_yaml::from_str("name: test\nauthor: Me\ntitle: Test\n").unwrap();
+        epub_info.assets = vec![image("cover.jpg", "cover"), image("map.png", "asset-images-map-png")];
+        let opf = create_content_opf_content(&epub_info, &[]);
+        assert!(opf.contains(r#"<meta name="cover" content="cover"/>"#));
+        assert!(opf.contains(r#"<item id="cover" href="images/cover.jpg" media-type="image/jpeg" properties="cover-image"/>"#));
+        assert!(opf.contains(r#"<item id="asset-images-map-png" href="images/map.png" media-type="image/png"/>"#));
+
+        epub_info.assets = vec![image("map.png", "asset-images-map-png")];
+        let opf = create_content_opf_content(&epub_info, &[]);
+        assert!(!opf.contains("cover"));
+    }
+}
@@ -0,0 +1,270 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+
+use serde_json::{json, Value};
+
+use crate::audit::Audit;
+use crate::diagnostics::Diagnostic;
+use crate::preprocess::{Chapter, Preprocessor};
+use crate::types::{EpubInfo, ExternalPreprocessor, Scope, Source};
+
+// External preprocessors are programs declared in book.yaml:
+//
+//     preprocess:
+//       external:
+//         - name: house-style
+//           command: tools/house-style.py
+//           scope: chapter
+//
+// They run in the book folder and speak JSON whatever their scope. On stdin
+// they get
+//
+//     {"book": {"title", "author", "language"},
+//      "chapters": [{"name", "path", "content"}, ...]}
+//
+// where a chapter preprocessor is handed one chapter at a time and a book
+// preprocessor all of them, and on stdout they print
+//
+//     {"chapters": [{"name", "content"}, ...]}
+//
+// listing only the chapters they changed. Anything written to stderr is shown
+// as is; when the program fails the content is left unchanged.
+pub struct External {
+    name: String,
+    command: String,
+    args: Vec<String>,
+    book_folder: String,
+    book: Value,
+}
+
+impl External {
+    pub fn new(config: &ExternalPreprocessor, epub_info: &EpubInfo, book_folder: &str) -> External {
+        // a command found in the book folder wins over one on the PATH; the
+        // program runs in the book folder, so its path must not be relative
+        let local = Path::new(book_folder).join(&config.command);
+        let command = match local.is_file() {
+            true => fs::canonicalize(&local).unwrap_or(local).to_string_lossy().to_string(),
+            false => config.command.clone(),
+        };
+
+        External {
+            name: config.name.clone(),
+            command,
+            args: config.args.clone(),
+            book_folder: book_folder.to_string(),
+            book: json!({
+                "title": epub_info.title,
+                "author": epub_info.author,
+                "language": epub_info.language(),
+            }),
+        }
+    }
+
+    fn invoke(&self, input: &Value) -> Result<String, String> {
+        let mut child = Command::new(&self.command)
+            .args(&self.args)
+            .current_dir(&self.book_folder)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|err| format!("could not run {}: {}", self.command, err))?;
+
+        // write from another thread so a program answering before it has
+        // read all its input cannot block on a full pipe
+        let mut stdin = child.stdin.take().unwrap();
+        let input = input.to_string();
+        let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+
+        let output = child.wait_with_output().map_err(|err| err.to_string())?;
+        // a program is free to stop reading early
+        let _ = writer.join();
+
+        if !output.status.success() {
+            return Err(format!("{} exited with {}", self.command, output.status));
+        }
+
+        String::from_utf8(output.stdout).map_err(|_| String::from("output is not valid UTF-8"))
+    }
+
+    // Hand the chapters to the program, returning the name and new content of
+    // each chapter it changed
+    fn exchange(&self, chapters: Vec<Value>) -> Result<Vec<(String, String)>, String> {
+        let output = self.invoke(&json!({ "book": self.book, "chapters": chapters }))?;
+        let output: Value = serde_json::from_str(&output).map_err(|err| format!("invalid JSON output: {}", err))?;
+
+        let changed = output["chapters"]
+            .as_array()
+            .ok_or_else(|| String::from("output has no \"chapters\" list"))?;
+
+        changed
+            .iter()
+            .map(|chapter| match (chapter["name"].as_str(), chapter["content"].as_str()) {
+                (Some(name), Some(content)) => Ok((name.to_string(), content.to_string())),
+                _ => Err(String::from("every chapter needs a \"name\" and a \"content\"")),
+            })
+            .collect()
+    }
+
+    fn run_book(&self, sources: &mut [Source], mut audit: Option<&mut Audit>) -> Result<(), String> {
+        let chapters: Vec<Value> = sources
+            .iter()
+            .map(|source| json!({ "name": source.name, "path": source.path, "content": source.content }))
+            .collect();
+
+        for (name, content) in self.exchange(chapters)? {
+            match sources.iter_mut().find(|source| source.name == name) {
+                Some(source) => source.update(&self.name, content, audit.as_deref_mut()),
+                None => self.unknown_chapter(&name),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn unknown_chapter(&self, name: &str) {
+        Diagnostic::warning(format!("preprocessor \"{}\" returned unknown chapter \"{}\"", self.name, name)).emit();
+    }
+}
+
+impl Preprocessor for External {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn run(&self, markdown: &str, chapter: &Chapter) -> Result<String, String> {
+        let mut content = markdown.to_string();
+
+        let chapters = vec![json!({ "name": chapter.name, "path": chapter.path, "content": markdown })];
+        for (name, changed) in self.exchange(chapters)? {
+            if name == chapter.name {
+                content = changed;
+            } else {
+                self.unknown_chapter(&name);
+            }
+        }
+
+        Ok(content)
+    }
+}
+
+// Run the book-scope preprocessors over all chapters, in the order they are
+// declared
+pub fn run_book_preprocessors(
+    epub_info: &EpubInfo,
+    book_folder: &str,
+    sources: &mut [Source],
+    mut audit: Option<&mut Audit>,
+) {
+    for config in &epub_info.preprocess.external {
+        if config.scope != Scope::Book {
+            continue;
+        }
+
+        let external = External::new(config, epub_info, book_folder);
+        if let Err(err) = external.run_book(sources, audit.as_deref_mut()) {
+            Diagnostic::warning(format!("preprocessor \"{}\" failed: {}", external.name, err))
+                .in_config(&config.command)
+                .emit();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::LineMap;
+
+    fn book() -> EpubInfo {
+        serde_yaml::from_str("name: test\nauthor: Me\ntitle: Test\n").unwrap()
+    }
+
+    // A preprocessor running a shell script
+    fn shell(script: &str, scope: Scope) -> ExternalPreprocessor {
+        ExternalPreprocessor {
+            name: String::from("script"),
+            command: String::from("sh"),
+            args: vec![String::from("-c"), script.to_string()],
+            scope,
+        }
+    }
+
+    fn run_chapter(script: &str, markdown: &str) -> Result<String, String> {
+        let external = External::new(&shell(script, Scope::Chapter), &book(), ".");
+        let line_map = LineMap::new(markdown);
+        external.run(markdown, &Chapter { name: "one", path: "one.md", line_map: &line_map })
+    }
+
+    #[test]
+    fn chapter_protocol() {
+        // the program gets the book and the chapter on stdin
+        let echo = r#"printf '{"chapters": [{"name": "one", "content": "%s"}]}' "$(sed 's/[\\"]/\\&/g')""#;
+        let input: Value = serde_json::from_str(&run_chapter(echo, "Hello").unwrap()).unwrap();
+        assert_eq!(input["book"]["title"], "Test");
+        assert_eq!(input["book"]["language"], "en");
+        assert_eq!(input["chapters"], json!([{ "name": "one", "path": "one.md", "content": "Hello" }]));
+
+        // chapters left out of the output are unchanged
+        assert_eq!(run_chapter(r#"cat > /dev/null; echo '{"chapters": []}'"#, "Hello").unwrap(), "Hello");
+    }
+
+    #[test]
+    fn failures_leave_the_content_unchanged() {
+        let cases = [
+            ("cat > /dev/null; exit 3", "exited with"),
+            ("cat > /dev/null; echo 'not JSON'", "invalid JSON output"),
+            (r#"cat > /dev/null; echo '{"changed": []}'"#, "no \"chapters\" list"),
+            (r#"cat > /dev/null; echo '{"chapters": [{"name": "one"}]}'"#, "needs a \"name\" and a \"content\""),
+        ];
+        for (script, error) in cases {
+            let result = run_chapter(script, "Hello");
+            assert!(result.as_ref().is_err_and(|err| err.contains(error)), "{}: {:?}", script, result);
+        }
+    }
+
+    #[test]
+    fn book_protocol() {
+        let mut sources = vec![
+            Source::new(String::from("one"), String::from("one.md"), String::from("One")),
+            Source::new(String::from("two"), String::from("two.md"), String::from("Two")),
+        ];
+        // every chapter is handed over at once; only those returned change,
+        // and unknown ones are passed over
+        let script = r#"grep -q '"two.md"' && echo '{"chapters": [{"name": "two", "content": "2"}, {"name": "three", "content": "3"}]}'"#;
+        let external = External::new(&shell(script, Scope::Book), &book(), ".");
+        external.run_book(&mut sources, None).unwrap();
+
+        let contents: Vec<&str> = sources.iter().map(|source| source.content.as_str()).collect();
+        assert_eq!(contents, ["One", "2"]);
+    }
+
+    #[test]
+    fn local_command_with_relative_book_folder() {
+        // cargo runs tests in the crate folder, so this path is relative
+        let folder = format!("target/mkepub-external-{}", std::process::id());
+        fs::create_dir_all(Path::new(&folder).join("tools")).unwrap();
+        let script = Path::new(&folder).join("tools/up.sh");
+        fs::write(&script, "#!/bin/sh\ncat > /dev/null\necho '{\"chapters\": [{\"name\": \"one\", \"content\": \"up\"}]}'\n").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        let config = ExternalPreprocessor {
+            name: String::from("up"),
+            command: String::from("tools/up.sh"),
+            args: Vec::new(),
+            scope: Scope::Chapter,
+        };
+        let external = External::new(&config, &book(), &folder);
+        let line_map = LineMap::new("down");
+        let result = external.run("down", &Chapter { name: "one", path: "one.md", line_map: &line_map });
+
+        fs::remove_dir_all(&folder).unwrap();
+        assert_eq!(result.unwrap(), "up");
+    }
+}
@@ -0,0 +1,782 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use regex::Regex;
+
+use crate::diagnostics::{self, Diagnostic};
+use crate::links::xhtml_files;
+use crate::media::Kind;
+use crate::types::{Asset, EmbeddingCheck, EpubInfo};
+
+// Where the generated @font-face rules go inside OPS
+pub const FONTS_CSS: &str = "css/fonts.css";
+
+// Characters kept in every subset, so punctuation the typography rules or a
+// reading system may add still has a glyph
+const SAFETY_SET: &str = " !\"#$%&'()*+,-./0123456789:;<=>?@ABCDEFGHIJKLMNOPQRSTUVWXYZ[\\]^_`abcdefghijklmnopqrstuvwxyz{|}~\u{A0}\u{AD}\u{2009}\u{202F}‘’‚“”„–—‐‑…•·«»‹›¡¿°×©®™€£";
+
+// What the name and OS/2 tables of a font file say about it
+#[derive(Debug, Clone)]
+pub struct FontInfo {
+    pub family: String,
+    // 100 to 900
+    pub weight: u16,
+    pub italic: bool,
+    // 1 (ultra-condensed) to 9 (ultra-expanded), 5 being normal
+    pub width: u16,
+    // embedding permissions of the OS/2 table; unknown for a font described
+    // from its file name
+    pub fs_type: Option<u16>,
+    pub license: Option<String>,
+    pub license_url: Option<String>,
+}
+
+// fsType bits
+const RESTRICTED: u16 = 0x0002;
+const PREVIEW_AND_PRINT: u16 = 0x0004;
+const EDITABLE: u16 = 0x0008;
+const NO_SUBSETTING: u16 = 0x0100;
+const BITMAP_ONLY: u16 = 0x0200;
+
+// A font of the book and the face it holds
+#[derive(Debug, Clone)]
+pub struct Font {
+    pub source: String,
+    pub href: String,
+    pub info: FontInfo,
+}
+
+// Read the faces of the book's fonts. A file whose tables cannot be read,
+// such as a WOFF, is described from its file name instead.
+pub fn read_fonts(book_folder: &str, assets: &[Asset]) -> Vec<Font> {
+    assets
+        .iter()
+        .filter(|asset| asset.media_type.kind == Kind::Font)
+        .map(|asset| {
+            let data = fs::read(Path::new(book_folder).join(&asset.source)).unwrap_or_default();
+            let info = parse(&data).unwrap_or_else(|| {
+                let guessed = guess_from_name(&asset.source);
+                Diagnostic::warning(format!(
+                    "the name table of {} could not be read, taking it for {} {}{}",
+                    asset.source,
+                    guessed.family,
+                    guessed.weight,
+                    if guessed.italic { " italic" } else { "" }
+                ))
+                .in_file(&asset.source)
+                .emit();
+                guessed
+            });
+
+            Font { source: asset.source.clone(), href: asset.href.clone(), info }
+        })
+        .collect()
+}
+
+// Report the fonts whose fsType does not allow them to be embedded in a
+// book, as a warning or an error as book.yaml asks
+pub fn check_embedding(epub_info: &EpubInfo) {
+    if epub_info.fonts.embedding == EmbeddingCheck::Ignore {
+        return;
+    }
+
+    for font in &epub_info.font_faces {
+        let problem = match embedding(font.info.fs_type) {
+            Embedding::Allowed => continue,
+            // a font without an OS/2 table, or one that could not be read,
+            // says nothing about embedding
+            Embedding::Unknown => {
+                Diagnostic::warning(format!(
+                    "{} ({}) has no embedding permission that could be read, check its licence",
+                    font.source, font.info.family
+                ))
+                .in_file(&font.source)
+                .emit();
+                continue;
+            }
+            Embedding::Forbidden(problem) => problem,
+        };
+
+        let message = format!("{} ({}) may not be embedded: {}", font.source, font.info.family, problem);
+        let diagnostic = match epub_info.fonts.embedding {
+            EmbeddingCheck::Error => Diagnostic::error(message),
+            _ => Diagnostic::warning(message),
+        };
+        diagnostic.in_file(&font.source).emit();
+    }
+}
+
+// Whether the fsType of a font lets it be embedded in a book
+#[derive(Debug, PartialEq)]
+enum Embedding {
+    Allowed,
+    // there is no fsType to go by
+    Unknown,
+    // not allowed, and why
+    Forbidden(&'static str),
+}
+
+fn embedding(fs_type: Option<u16>) -> Embedding {
+    let Some(fs_type) = fs_type else {
+        return Embedding::Unknown;
+    };
+
+    if fs_type & RESTRICTED != 0 && fs_type & (PREVIEW_AND_PRINT | EDITABLE) == 0 {
+        Embedding::Forbidden("its licence restricts embedding")
+    } else if fs_type & PREVIEW_AND_PRINT != 0 && fs_type & EDITABLE == 0 {
+        Embedding::Forbidden("its licence allows embedding for preview and print only")
+    } else {
+        Embedding::Allowed
+    }
+}
+
+// What the fsType of a font allows
+fn permission(fs_type: Option<u16>) -> String {
+    let Some(fs_type) = fs_type else {
+        return String::from("unknown");
+    };
+
+    let mut permission = String::from(if fs_type & EDITABLE != 0 {
+        "editable"
+    } else if fs_type & PREVIEW_AND_PRINT != 0 {
+        "preview and print"
+    } else if fs_type & RESTRICTED != 0 {
+        "restricted"
+    } else {
+        "installable"
+    });
+    if fs_type & NO_SUBSETTING != 0 {
+        permission.push_str(", no subsetting");
+    }
+    if fs_type & BITMAP_ONLY != 0 {
+        permission.push_str(", bitmap only");
+    }
+    permission
+}
+
+// List each font with its embedding permission and licence
+pub fn report_fonts(epub_info: &EpubInfo) {
+    if epub_info.font_faces.is_empty() {
+        return;
+    }
+
+    diagnostics::info("Fonts:");
+    for font in &epub_info.font_faces {
+        // the licence field often holds a paragraph, shown in full
+        let license = match &font.info.license {
+            Some(license) => license.split_whitespace().collect::<Vec<&str>>().join(" "),
+            None => String::from("no licence given"),
+        };
+        let url = font.info.license_url.as_ref().map_or(String::new(), |url| format!(" <{}>", url));
+
+        diagnostics::info(&format!(
+            "  {}: {} {}{}, embedding {}, {}{}",
+            font.href,
+            font.info.family,
+            font.info.weight,
+            if font.info.italic { " italic" } else { "" },
+            permission(font.info.fs_type),
+            license,
+            url
+        ));
+    }
+}
+
+// Write an @font-face rule for each font, so pages can use them by family
+pub fn create_fonts_css(epub_info: &EpubInfo, dest_folder: &str) {
+    if epub_info.font_faces.is_empty() {
+        return;
+    }
+
+    let css = fonts_css(&epub_info.font_faces);
+    let css_path = Path::new(dest_folder).join("OPS").join(FONTS_CSS);
+    if let Some(parent) = css_path.parent() {
+        let _ = fs::create_dir_all(parent);
+    }
+
+    fs::write(&css_path, css)
+        .unwrap_or_else(|err| Diagnostic::error(format!("failed to write {}: {}", FONTS_CSS, err)).emit());
+}
+
+// An @font-face rule for each font, with the descriptors its tables give
+fn fonts_css(fonts: &[Font]) -> String {
+    let mut css = String::from("/* Generated by mkepub from the fonts of the book */\n");
+    for font in fonts {
+        css.push_str(&format!(
+            "\n@font-face {{\n  font-family: \"{}\";\n  src: url(\"../{}\");\n  font-weight: {};\n  font-style: {};\n",
+            font.info.family.replace('"', "\\\""),
+            font.href,
+            font.info.weight,
+            if font.info.italic { "italic" } else { "normal" }
+        ));
+        if font.info.width != 5 {
+            css.push_str(&format!("  font-stretch: {};\n", stretch(font.info.width)));
+        }
+        css.push_str("}\n");
+    }
+
+    css
+}
+
+// Cut the TTF and OTF fonts copied into OPS down to the characters of the
+// book's text, plus a safety set and the characters in `fonts.keep`. Every
+// font keeps every character the book uses, as telling which text ends up
+// in which face would take the whole CSS cascade. The subsetting is done by
+// fontTools' pyftsubset; a font it fails on is left whole.
+pub fn subset_fonts(epub_info: &EpubInfo, book_folder: &str, dest_folder: &str) {
+    let config = &epub_info.fonts;
+    if !config.subset || epub_info.font_faces.is_empty() {
+        return;
+    }
+
+    let ops_path = Path::new(dest_folder).join("OPS");
+    let characters = subset_characters(&ops_path, &config.keep);
+
+    // kept out of the book folder and the package
+    let text_path = std::env::temp_dir().join(format!("mkepub-subset-{}.txt", std::process::id()));
+    if let Err(err) = fs::write(&text_path, characters.iter().collect::<String>()) {
+        Diagnostic::error(format!("failed to write the characters to keep in the fonts: {}", err)).emit();
+        return;
+    }
+
+    // a command found in the book folder wins over one on the PATH
+    let local = Path::new(book_folder).join(&config.command);
+    let command = match local.is_file() {
+        true => fs::canonicalize(&local).unwrap_or(local),
+        false => PathBuf::from(&config.command),
+    };
+
+    let (mut total_before, mut total_after) = (0, 0);
+    for font in &epub_info.font_faces {
+        match left_whole(font) {
+            Some(LeftWhole::Format) => continue,
+            Some(LeftWhole::Licence) => {
+                Diagnostic::warning(format!("the licence of {} does not allow subsetting, it is left whole", font.source))
+                    .in_file(&font.source)
+                    .emit();
+                continue;
+            }
+            None => {}
+        }
+
+        let path = ops_path.join(&font.href);
+        let extension = path.extension().unwrap_or_default().to_string_lossy().to_lowercase();
+
+        let output_path = path.with_extension(format!("subset.{}", extension));
+        let result = Command::new(&command)
+            .arg(&path)
+            .arg(format!("--text-file={}", text_path.display()))
+            .arg(format!("--output-file={}", output_path.display()))
+            .args(["--layout-features=*", "--name-IDs=*", "--name-languages=*", "--notdef-outline"])
+            .status();
+
+        let status = match result {
+            Ok(status) => status,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                Diagnostic::warning(format!(
+                    "{} was not found, fonts are not subset; install fontTools or set fonts.command",
+                    config.command
+                ))
+                .in_config("subset")
+                .emit();
+                break;
+            }
+            Err(err) => {
+                Diagnostic::warning(format!("could not run {}: {}", command.display(), err)).in_config("subset").emit();
+                break;
+            }
+        };
+
+        let before = fs::metadata(&path).map_or(0, |metadata| metadata.len());
+        let replaced = status.success() && fs::rename(&output_path, &path).is_ok();
+        if !replaced {
+            let _ = fs::remove_file(&output_path);
+            Diagnostic::warning(format!("{} could not be subset ({}), it is left whole", font.href, status)).emit();
+        }
+        let after = fs::metadata(&path).map_or(0, |metadata| metadata.len());
+
+        diagnostics::info(&format!("Subset {}: {} -> {}", font.href, kilobytes(before), kilobytes(after)));
+        total_before += before;
+        total_after += after;
+    }
+
+    let _ = fs::remove_file(&text_path);
+    if total_before > 0 {
+        diagnostics::info(&format!(
+            "Fonts subset to {} characters: {} -> {}",
+            characters.len(),
+            kilobytes(total_before),
+            kilobytes(total_after)
+        ));
+    }
+}
+
+// Why a font is not subset
+#[derive(Debug, PartialEq)]
+enum LeftWhole {
+    // only TTF and OTF files are; WOFF and WOFF2 are compressed already
+    Format,
+    // its fsType does not allow it
+    Licence,
+}
+
+fn left_whole(font: &Font) -> Option<LeftWhole> {
+    let extension = Path::new(&font.href).extension().unwrap_or_default().to_string_lossy().to_lowercase();
+    if extension != "ttf" && extension != "otf" {
+        Some(LeftWhole::Format)
+    } else if font.info.fs_type.is_some_and(|fs_type| fs_type & NO_SUBSETTING != 0) {
+        Some(LeftWhole::Licence)
+    } else {
+        None
+    }
+}
+
+// The characters the subsets keep: the text of the pages, what stylesheets
+// put on the page, the safety set and `fonts.keep`
+fn subset_characters(ops_path: &Path, keep: &str) -> BTreeSet<char> {
+    let mut characters: BTreeSet<char> = SAFETY_SET.chars().chain(keep.chars()).collect();
+    for file_path in xhtml_files(ops_path) {
+        characters.extend(page_text(&fs::read_to_string(&file_path).unwrap_or_default()).chars());
+    }
+    for file_path in css_files(ops_path) {
+        characters.extend(css_content(&fs::read_to_string(&file_path).unwrap_or_default()).chars());
+    }
+    characters.retain(|c| !c.is_control());
+    characters
+}
+
+fn kilobytes(bytes: u64) -> String {
+    format!("{:.1} KB", bytes as f64 / 1024.0)
+}
+
+// The text of an XHTML file, with its tags taken out and entities decoded
+fn page_text(content: &str) -> String {
+    let tag_re = Regex::new(r"<[^>]*>").unwrap();
+    let entity_re = Regex::new(r"&(#[0-9]+|#x[0-9A-Fa-f]+|[A-Za-z]+);").unwrap();
+    let text = tag_re.replace_all(content, "");
+
+    entity_re
+        .replace_all(&text, |caps: &regex::Captures| {
+            let entity = &caps[1];
+            let c = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some('\u{A0}'),
+                _ => match entity.strip_prefix("#x").or(entity.strip_prefix("#X")) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                    None => entity.strip_prefix('#').and_then(|n| n.parse().ok()).and_then(char::from_u32),
+                },
+            };
+            c.map_or(caps[0].to_string(), String::from)
+        })
+        .into_owned()
+}
+
+// Text a stylesheet puts on the page through `content:`
+fn css_content(css: &str) -> String {
+    let content_re = Regex::new(r#"content\s*:\s*(?:"([^"]*)"|'([^']*)')"#).unwrap();
+    content_re
+        .captures_iter(css)
+        .filter_map(|caps| caps.get(1).or(caps.get(2)))
+        .map(|text| text.as_str())
+        .collect()
+}
+
+fn css_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            if path.is_dir() {
+                files.extend(css_files(&path));
+            } else if path.extension() == Some("css".as_ref()) {
+                files.push(path);
+            }
+        }
+    }
+
+    files
+}
+
+fn stretch(width: u16) -> &'static str {
+    match width {
+        1 => "ultra-condensed",
+        2 => "extra-condensed",
+        3 => "condensed",
+        4 => "semi-condensed",
+        6 => "semi-expanded",
+        7 => "expanded",
+        8 => "extra-expanded",
+        9 => "ultra-expanded",
+        _ => "normal",
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4).map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// The bytes of table `tag` of a TrueType or OpenType font. Of a collection,
+// the first font is read.
+pub fn table<'a>(data: &'a [u8], tag: &[u8; 4]) -> Option<&'a [u8]> {
+    let start = match data.get(0..4)? {
+        b"ttcf" => read_u32(data, 12)? as usize,
+        _ => 0,
+    };
+
+    let tables = read_u16(data, start + 4)? as usize;
+    (0..tables).find_map(|i| {
+        let record = start + 12 + i * 16;
+        if data.get(record..record + 4)? != tag {
+            return None;
+        }
+        let offset = read_u32(data, record + 8)? as usize;
+        let length = read_u32(data, record + 12)? as usize;
+        data.get(offset..offset + length)
+    })
+}
+
+// A string of the name table, preferring US English Windows names
+pub fn name(data: &[u8], name_id: u16) -> Option<String> {
+    let table = table(data, b"name")?;
+    let count = read_u16(table, 2)? as usize;
+    let strings = read_u16(table, 4)? as usize;
+
+    let mut best: Option<(u8, String)> = None;
+    for i in 0..count {
+        let record = 6 + i * 12;
+        if read_u16(table, record + 6)? != name_id {
+            continue;
+        }
+
+        let platform = read_u16(table, record)?;
+        let encoding = read_u16(table, record + 2)?;
+        let language = read_u16(table, record + 4)?;
+        let length = read_u16(table, record + 8)? as usize;
+        let offset = strings + read_u16(table, record + 10)? as usize;
+        let Some(bytes) = table.get(offset..offset + length) else {
+            continue;
+        };
+
+        let (rank, text) = match (platform, encoding) {
+            (3, 1) | (3, 10) | (0, _) => {
+                let units: Vec<u16> = bytes.chunks_exact(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect();
+                let rank = if platform == 3 && language == 0x409 { 0 } else { 1 };
+                (rank, String::from_utf16_lossy(&units))
+            }
+            // Mac Roman; names are ASCII in practice
+            (1, 0) => (2, bytes.iter().map(|&b| b as char).collect()),
+            _ => continue,
+        };
+
+        if best.as_ref().is_none_or(|(best_rank, _)| rank < *best_rank) {
+            best = Some((rank, text));
+        }
+    }
+
+    best.map(|(_, text)| text.trim().to_string()).filter(|text| !text.is_empty())
+}
+
+fn parse(data: &[u8]) -> Option<FontInfo> {
+    // the typographic family groups all weights; the legacy one stops at four styles
+    let family = name(data, 16).or_else(|| name(data, 1))?;
+    let os2 = table(data, b"OS/2")?;
+    let fs_selection = read_u16(os2, 62).unwrap_or(0);
+
+    Some(FontInfo {
+        family,
+        weight: read_u16(os2, 4)?.clamp(100, 900),
+        // italic, or oblique
+        italic: fs_selection & 0x0201 != 0,
+        width: read_u16(os2, 6).filter(|width| (1..=9).contains(width)).unwrap_or(5),
+        fs_type: read_u16(os2, 8),
+        license: name(data, 13),
+        license_url: name(data, 14),
+    })
+}
+
+// Gelasio-BoldItalic.woff2 is taken for Gelasio, 700, italic
+fn guess_from_name(source: &str) -> FontInfo {
+    let stem = Path::new(source).file_stem().unwrap_or_default().to_string_lossy().to_string();
+    let (family, style) = stem.split_once('-').unwrap_or((&stem, ""));
+    let style = style.to_lowercase();
+
+    let weight = [
+        ("thin", 100),
+        ("extralight", 200),
+        ("light", 300),
+        ("medium", 500),
+        ("semibold", 600),
+        ("extrabold", 800),
+        ("bold", 700),
+        ("black", 900),
+    ]
+    .iter()
+    .find(|(word, _)| style.contains(word))
+    .map_or(400, |&(_, weight)| weight);
+
+    FontInfo {
+        family: family.to_string(),
+        weight,
+        italic: style.contains("italic") || style.contains("oblique"),
+        width: 5,
+        fs_type: None,
+        license: None,
+        license_url: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn changeover_font(name: &str) -> Vec<u8> {
+        fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("changeover/OPS/fonts").join(name)).unwrap()
+    }
+
+    // A font file holding the given tables, with just enough of a header
+    fn font_file(tables: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut data = vec![0, 1, 0, 0];
+        data.extend((tables.len() as u16).to_be_bytes());
+        data.extend([0; 6]);
+
+        let mut offset = 12 + tables.len() * 16;
+        for (tag, table) in tables {
+            data.extend(*tag);
+            data.extend([0; 4]);
+            data.extend((offset as u32).to_be_bytes());
+            data.extend((table.len() as u32).to_be_bytes());
+            offset += table.len();
+        }
+        for (_, table) in tables {
+            data.extend(table);
+        }
+        data
+    }
+
+    // A name table with records of (platform, encoding, language, name id, text)
+    fn name_table(records: &[(u16, u16, u16, u16, &str)]) -> Vec<u8> {
+        let strings: Vec<Vec<u8>> = records
+            .iter()
+            .map(|&(platform, _, _, _, text)| match platform {
+                1 => text.bytes().collect(),
+                _ => text.encode_utf16().flat_map(u16::to_be_bytes).collect(),
+            })
+            .collect();
+
+        let mut table = Vec::new();
+        for value in [0, records.len() as u16, 6 + 12 * records.len() as u16] {
+            table.extend(value.to_be_bytes());
+        }
+        let mut offset = 0;
+        for (&(platform, encoding, language, name_id, _), bytes) in records.iter().zip(&strings) {
+            for value in [platform, encoding, language, name_id, bytes.len() as u16, offset] {
+                table.extend(value.to_be_bytes());
+            }
+            offset += bytes.len() as u16;
+        }
+        table.extend(strings.concat());
+        table
+    }
+
+    // An OS/2 table with the fields mkepub reads
+    fn os2_table(weight: u16, width: u16, fs_type: u16, fs_selection: u16) -> Vec<u8> {
+        let mut table = vec![0; 78];
+        for (offset, value) in [(4, weight), (6, width), (8, fs_type), (62, fs_selection)] {
+            table[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+        }
+        table
+    }
+
+    #[test]
+    fn reads_the_changeover_fonts() {
+        let info = parse(&changeover_font("CormorantGaramond-Medium.otf")).unwrap();
+        assert_eq!((info.family.as_str(), info.weight, info.italic, info.width), ("Cormorant Garamond", 500, false, 5));
+        assert!(info.license.unwrap().starts_with("This Font Software is licensed under the SIL Open Font License"));
+        assert_eq!(info.license_url.as_deref(), Some("http://scripts.sil.org/OFL"));
+
+        let info = parse(&changeover_font("Gelasio-Bold.otf")).unwrap();
+        assert_eq!((info.family.as_str(), info.weight, info.italic, info.width), ("Gelasio", 700, false, 5));
+    }
+
+    #[test]
+    fn tables_are_found_by_tag() {
+        let data = font_file(&[(b"head", vec![1, 2, 3]), (b"OS/2", vec![4, 5])]);
+        assert_eq!(table(&data, b"OS/2"), Some(&[4u8, 5][..]));
+        assert_eq!(table(&data, b"name"), None);
+        // a table running past the end of the file is not read
+        assert_eq!(table(&data[..data.len() - 1], b"OS/2"), None);
+        assert_eq!(table(b"OTTO", b"OS/2"), None);
+
+        // of a collection, the first font
+        let mut collection = b"ttcf".to_vec();
+        collection.extend([0, 1, 0, 0, 0, 0, 0, 1, 0, 0, 0, 16]);
+        collection.extend(font_file(&[(b"OS/2", vec![4, 5])]));
+        // offsets in a collection are from its start
+        let record = 16 + 12 + 8;
+        let offset = u32::from_be_bytes(collection[record..record + 4].try_into().unwrap()) + 16;
+        collection[record..record + 4].copy_from_slice(&offset.to_be_bytes());
+        assert_eq!(table(&collection, b"OS/2"), Some(&[4u8, 5][..]));
+    }
+
+    #[test]
+    fn names_prefer_us_english_windows_strings() {
+        let data = font_file(&[(
+            b"name",
+            name_table(&[
+                (1, 0, 0, 1, "Mac Name"),
+                (3, 1, 0x407, 1, "Deutscher Name"),
+                (3, 1, 0x409, 1, "Windows Name"),
+                (1, 0, 0, 16, "Mac Family"),
+                (3, 1, 0x409, 13, "  "),
+            ]),
+        )]);
+        assert_eq!(name(&data, 1).as_deref(), Some("Windows Name"));
+        assert_eq!(name(&data, 16).as_deref(), Some("Mac Family"));
+        // blank and missing names are none
+        assert_eq!(name(&data, 13), None);
+        assert_eq!(name(&data, 14), None);
+    }
+
+    #[test]
+    fn weight_style_and_width() {
+        let font = |os2: Vec<u8>| {
+            let names = name_table(&[(3, 1, 0x409, 1, "Legacy"), (3, 1, 0x409, 16, "Typographic")]);
+            parse(&font_file(&[(b"name", names), (b"OS/2", os2)])).unwrap()
+        };
+
+        let info = font(os2_table(400, 5, 0, 0));
+        assert_eq!((info.family.as_str(), info.weight, info.italic, info.width, info.fs_type), ("Typographic", 400, false, 5, Some(0)));
+        // italic and oblique
+        assert!(font(os2_table(400, 5, 0, 0x0001)).italic);
+        assert!(font(os2_table(400, 5, 0, 0x0200)).italic);
+        // out of range weights and widths
+        assert_eq!(font(os2_table(1000, 0, 0, 0)).weight, 900);
+        assert_eq!(font(os2_table(1, 12, 0, 0)).weight, 100);
+        assert_eq!(font(os2_table(400, 12, 0, 0)).width, 5);
+        assert_eq!(font(os2_table(400, 3, 0, 0)).width, 3);
+
+        // the legacy family when there is no typographic one
+        let names = name_table(&[(3, 1, 0x409, 1, "Legacy")]);
+        assert_eq!(parse(&font_file(&[(b"name", names), (b"OS/2", os2_table(400, 5, 0, 0))])).unwrap().family, "Legacy");
+        // no OS/2 table, no face
+        let names = name_table(&[(3, 1, 0x409, 1, "Legacy")]);
+        assert!(parse(&font_file(&[(b"name", names)])).is_none());
+    }
+
+    #[test]
+    fn file_name_fallback() {
+        let cases = [
+            ("fonts/Gelasio-BoldItalic.woff2", "Gelasio", 700, true),
+            ("Gelasio-ExtraBold.woff", "Gelasio", 800, false),
+            ("Gelasio-SemiBoldOblique.ttf", "Gelasio", 600, true),
+            ("Gelasio-Regular.ttf", "Gelasio", 400, false),
+            ("Gelasio.ttf", "Gelasio", 400, false),
+            ("Cormorant-Light.otf", "Cormorant", 300, false),
+        ];
+        for (source, family, weight, italic) in cases {
+            let info = guess_from_name(source);
+            assert_eq!((info.family.as_str(), info.weight, info.italic, info.fs_type), (family, weight, italic, None), "{}", source);
+        }
+    }
+
+    #[test]
+    fn characters_to_keep() {
+        let ops = std::env::temp_dir().join(format!("mkepub-subset-test-{}", std::process::id()));
+        fs::create_dir_all(ops.join("content")).unwrap();
+        fs::create_dir_all(ops.join("css")).unwrap();
+        fs::write(
+            ops.join("content/a.xhtml"),
+            "<html><body>\n<p title=\"Жук\">Ω&#233;&#x263A;&amp;&nope;</p>\n</body></html>",
+        )
+        .unwrap();
+        fs::write(ops.join("css/book.css"), "p::before { content: \"§\" } p::after { content: '¶' }").unwrap();
+
+        let characters = subset_characters(&ops, "₹");
+        fs::remove_dir_all(&ops).unwrap();
+
+        for c in ['Ω', 'é', '☺', '&', '§', '¶', '₹'] {
+            assert!(characters.contains(&c), "{}", c);
+        }
+        // the safety set is always kept, soft hyphens included
+        assert!(SAFETY_SET.chars().all(|c| characters.contains(&c)));
+        assert!(characters.contains(&'\u{AD}'));
+        // tags and control characters are not text
+        assert!(!characters.contains(&'Ж'));
+        assert!(!characters.contains(&'\n'));
+    }
+
+    #[test]
+    fn fonts_left_whole() {
+        let font = |href: &str, fs_type: Option<u16>| Font {
+            source: href.to_string(),
+            href: href.to_string(),
+            info: FontInfo { fs_type, ..guess_from_name(href) },
+        };
+        let cases = [
+            ("fonts/A.otf", Some(0), None),
+            ("fonts/A.TTF", None, None),
+            ("fonts/A.otf", Some(NO_SUBSETTING), Some(LeftWhole::Licence)),
+            ("fonts/A.ttf", Some(NO_SUBSETTING | EDITABLE), Some(LeftWhole::Licence)),
+            ("fonts/A.woff", Some(0), Some(LeftWhole::Format)),
+            ("fonts/A.woff2", Some(NO_SUBSETTING), Some(LeftWhole::Format)),
+        ];
+        for (href, fs_type, expected) in cases {
+            assert_eq!(left_whole(&font(href, fs_type)), expected, "{} {:?}", href, fs_type);
+        }
+    }
+
+    #[test]
+    fn embedding_permissions() {
+        let restricted = "its licence restricts embedding";
+        let preview = "its licence allows embedding for preview and print only";
+        let cases = [
+            (Some(0x0000), "installable", Embedding::Allowed),
+            (Some(RESTRICTED), "restricted", Embedding::Forbidden(restricted)),
+            (Some(PREVIEW_AND_PRINT), "preview and print", Embedding::Forbidden(preview)),
+            (Some(EDITABLE), "editable", Embedding::Allowed),
+            // the least restrictive bit wins
+            (Some(RESTRICTED | PREVIEW_AND_PRINT), "preview and print", Embedding::Forbidden(preview)),
+            (Some(RESTRICTED | EDITABLE), "editable", Embedding::Allowed),
+            (Some(NO_SUBSETTING), "installable, no subsetting", Embedding::Allowed),
+            (Some(BITMAP_ONLY | EDITABLE), "editable, bitmap only", Embedding::Allowed),
+            (Some(RESTRICTED | NO_SUBSETTING | BITMAP_ONLY), "restricted, no subsetting, bitmap only", Embedding::Forbidden(restricted)),
+            (None, "unknown", Embedding::Unknown),
+        ];
+        for (fs_type, shown, expected) in cases {
+            assert_eq!(permission(fs_type), shown, "{:?}", fs_type);
+            assert_eq!(embedding(fs_type), expected, "{:?}", fs_type);
+        }
+    }
+
+    #[test]
+    fn font_face_rules() {
+        let font = |href: &str, family: &str, weight: u16, italic: bool, width: u16| Font {
+            source: href.to_string(),
+            href: href.to_string(),
+            info: FontInfo { family: family.to_string(), weight, italic, width, ..guess_from_name(href) },
+        };
+        let css = fonts_css(&[
+            font("fonts/Gelasio-Bold.otf", "Gelasio", 700, false, 5),
+            font("fonts/Odd-Italic.otf", "Odd \"Quoted\"", 400, true, 3),
+        ]);
+
+        assert_eq!(
+            css,
+            "/* Generated by mkepub from the fonts of the book */\n\
+            \n@font-face {\n  font-family: \"Gelasio\";\n  src: url(\"../fonts/Gelasio-Bold.otf\");\n  font-weight: 700;\n  font-style: normal;\n}\n\
+            \n@font-face {\n  font-family: \"Odd \\\"Quoted\\\"\";\n  src: url(\"../fonts/Odd-Italic.otf\");\n  font-weight: 400;\n  font-style: italic;\n  font-stretch: condensed;\n}\n"
+        );
+    }
+}
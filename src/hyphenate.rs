@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::diagnostics::Diagnostic;
+use crate::preprocess::{Chapter, Preprocessor};
+use crate::prose::{map_prose, BlockKind};
+use crate::types::{EpubInfo, HyphenationConfig};
+
+const SOFT_HYPHEN: char = '\u{AD}';
+
+// Liang's hyphenation patterns, as used by TeX. A pattern such as `hy3ph`
+// is stored as its letters ("hyph") and the digits between them.
+struct Patterns {
+    patterns: HashMap<String, Vec<u8>>,
+    longest: usize,
+    // whole words from \hyphenation{}, with the positions of their hyphens
+    exceptions: HashMap<String, Vec<usize>>,
+}
+
+impl Patterns {
+    // Read a TeX file with \patterns{} and \hyphenation{} groups, or a plain
+    // list of patterns such as the hyph-utf8 .pat.txt files
+    fn parse(text: &str) -> Patterns {
+        let text: String = text
+            .lines()
+            .map(|line| line.split('%').next().unwrap_or_default())
+            .collect::<Vec<&str>>()
+            .join("\n");
+
+        let (patterns, exceptions) = if text.contains("\\patterns{") {
+            (tex_group(&text, "\\patterns{"), tex_group(&text, "\\hyphenation{"))
+        } else {
+            (text.as_str(), "")
+        };
+
+        let mut parsed = Patterns { patterns: HashMap::new(), longest: 0, exceptions: HashMap::new() };
+
+        for pattern in patterns.split_whitespace() {
+            let mut letters = String::new();
+            let mut values = vec![0u8];
+            for c in pattern.chars() {
+                match c.to_digit(10) {
+                    Some(digit) => *values.last_mut().unwrap() = digit as u8,
+                    None => {
+                        letters.push(c);
+                        values.push(0);
+                    }
+                }
+            }
+            parsed.longest = parsed.longest.max(letters.chars().count());
+            parsed.patterns.insert(letters, values);
+        }
+
+        for word in exceptions.split_whitespace() {
+            let mut positions = Vec::new();
+            let mut letters = String::new();
+            for c in word.chars() {
+                if c == '-' {
+                    positions.push(letters.chars().count());
+                } else {
+                    letters.push(c);
+                }
+            }
+            parsed.exceptions.insert(letters.to_lowercase(), positions);
+        }
+
+        parsed
+    }
+
+    // Character positions in `word` where it may be broken
+    fn breaks(&self, word: &str) -> Vec<usize> {
+        let lower = word.to_lowercase();
+        if let Some(positions) = self.exceptions.get(&lower) {
+            return positions.clone();
+        }
+
+        let chars: Vec<char> = format!(".{}.", lower).chars().collect();
+        let mut points = vec![0u8; chars.len() + 1];
+
+        for start in 0..chars.len() {
+            for end in start + 1..=chars.len().min(start + self.longest) {
+                let part: String = chars[start..end].iter().collect();
+                if let Some(values) = self.patterns.get(&part) {
+                    for (k, &value) in values.iter().enumerate() {
+                        points[start + k] = points[start + k].max(value);
+                    }
+                }
+            }
+        }
+
+        // points[i + 1] is the value between letters i - 1 and i of the word
+        (1..lower.chars().count()).filter(|&i| points[i + 1] % 2 == 1).collect()
+    }
+}
+
+// The text inside `\name{...}`
+fn tex_group<'a>(text: &'a str, name: &str) -> &'a str {
+    match text.find(name) {
+        Some(start) => {
+            let rest = &text[start + name.len()..];
+            &rest[..rest.find('}').unwrap_or(rest.len())]
+        }
+        None => "",
+    }
+}
+
+// Insert soft hyphens into long words of prose so e-readers can break them.
+// Headings are left alone, as are URLs and e-mail addresses typed in the
+// text; code never reaches the pass. Run it after the other rules, since
+// the soft hyphens would get in the way of their patterns.
+pub struct Hyphenate {
+    patterns: Patterns,
+    min_word: usize,
+    left_min: usize,
+    right_min: usize,
+}
+
+impl Hyphenate {
+    pub fn new(epub_info: &EpubInfo, book_folder: &str) -> Option<Hyphenate> {
+        let config = &epub_info.hyphenation;
+        let path = match &config.patterns {
+            Some(path) => Path::new(book_folder).join(path),
+            None => default_patterns(book_folder, epub_info.language()),
+        };
+
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) => {
+                let diagnostic = Diagnostic::warning(format!(
+                    "hyphenation patterns {} could not be read ({}), words are not hyphenated",
+                    path.display(),
+                    err
+                ));
+                match &config.patterns {
+                    Some(patterns) => diagnostic.in_config(patterns).emit(),
+                    None => diagnostic.in_config("hyphenate").emit(),
+                }
+                return None;
+            }
+        };
+
+        Some(Hyphenate::with_patterns(Patterns::parse(&text), config))
+    }
+
+    fn with_patterns(patterns: Patterns, config: &HyphenationConfig) -> Hyphenate {
+        Hyphenate {
+            patterns,
+            min_word: config.min_word,
+            left_min: config.left_min.max(1),
+            right_min: config.right_min.max(1),
+        }
+    }
+
+    fn hyphenate_word(&self, word: &str, output: &mut String) {
+        let length = word.chars().count();
+        // acronyms, names like iPhone and codes like A4paper are best left whole
+        let mixed_case = word.chars().skip(1).any(char::is_uppercase);
+        let has_digits = word.chars().any(|c| c.is_numeric());
+        if length < self.min_word || mixed_case || has_digits {
+            output.push_str(word);
+            return;
+        }
+
+        let breaks: Vec<usize> = self
+            .patterns
+            .breaks(word)
+            .into_iter()
+            .filter(|&i| i >= self.left_min && length - i >= self.right_min)
+            .collect();
+
+        for (i, c) in word.chars().enumerate() {
+            if breaks.contains(&i) {
+                output.push(SOFT_HYPHEN);
+            }
+            output.push(c);
+        }
+    }
+
+    fn hyphenate_text(&self, text: &str) -> String {
+        let mut output = String::with_capacity(text.len() * 11 / 10);
+
+        for token in text.split_inclusive(char::is_whitespace) {
+            if token.contains("://") || token.starts_with("www.") || token.contains('@') || token.contains(SOFT_HYPHEN) {
+                output.push_str(token);
+                continue;
+            }
+
+            let mut word = String::new();
+            for c in token.chars() {
+                if c.is_alphanumeric() {
+                    word.push(c);
+                } else {
+                    self.hyphenate_word(&word, &mut output);
+                    word.clear();
+                    output.push(c);
+                }
+            }
+            self.hyphenate_word(&word, &mut output);
+        }
+
+        output
+    }
+}
+
+impl Preprocessor for Hyphenate {
+    fn name(&self) -> &str {
+        "hyphenate"
+    }
+
+    fn run(&self, markdown: &str, chapter: &Chapter) -> Result<String, String> {
+        Ok(map_prose(markdown, chapter, |prose, kind| {
+            if kind == BlockKind::Heading {
+                prose.to_string()
+            } else {
+                self.hyphenate_text(prose)
+            }
+        }))
+    }
+}
+
+// hyphenation/hyph-<language>.tex in the book folder, falling back from
+// en-gb to en
+fn default_patterns(book_folder: &str, language: &str) -> PathBuf {
+    let folder = Path::new(book_folder).join("hyphenation");
+    let language = language.to_lowercase();
+    let primary = language.split(['-', '_']).next().unwrap_or_default();
+
+    let candidates = [
+        format!("hyph-{}.tex", language),
+        format!("hyph-{}.pat.txt", language),
+        format!("hyph-{}.tex", primary),
+        format!("hyph-{}.pat.txt", primary),
+    ];
+
+    candidates
+        .iter()
+        .map(|name| folder.join(name))
+        .find(|path| path.is_file())
+        .unwrap_or_else(|| folder.join(&candidates[0]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::LineMap;
+
+    // The example patterns from Liang's thesis, enough for "hyphenation"
+    const PATTERNS: &str = "% a comment\n\\patterns{\nhy3ph he2n hena4 hen5at 1na n2at 1tio 2io o2n\n1ca 1ta 1ble 1ter\n}\n\\hyphenation{ta-ble pro-ject}\n";
+
+    fn hyphenate(config: HyphenationConfig) -> Hyphenate {
+        Hyphenate::with_patterns(Patterns::parse(PATTERNS), &config)
+    }
+
+    // Show soft hyphens as `-`
+    fn shown(text: &str) -> String {
+        text.replace(SOFT_HYPHEN, "-")
+    }
+
+    #[test]
+    fn break_positions() {
+        let patterns = Patterns::parse(PATTERNS);
+        assert_eq!(patterns.breaks("hyphenation"), [2, 6]);
+        assert_eq!(patterns.breaks("Hyphenation"), [2, 6]);
+        // exceptions win over patterns
+        assert_eq!(patterns.breaks("table"), [2]);
+        assert_eq!(patterns.breaks("Project"), [3]);
+        assert!(patterns.breaks("xyz").is_empty());
+    }
+
+    #[test]
+    fn plain_pattern_list() {
+        let patterns = Patterns::parse("hy3ph\nhe2n hena4 hen5at\n1na n2at 1tio 2io o2n\n");
+        assert_eq!(patterns.breaks("hyphenation"), [2, 6]);
+    }
+
+    #[test]
+    fn left_and_right_minimums() {
+        let config = HyphenationConfig { min_word: 1, left_min: 1, right_min: 1, ..HyphenationConfig::default() };
+        assert_eq!(shown(&hyphenate(config).hyphenate_text("hyphenation table")), "hy-phen-ation ta-ble");
+
+        let config = HyphenationConfig { min_word: 1, left_min: 3, right_min: 3, ..HyphenationConfig::default() };
+        assert_eq!(shown(&hyphenate(config).hyphenate_text("hyphenation table")), "hyphen-ation table");
+
+        let config = HyphenationConfig { min_word: 1, left_min: 2, right_min: 6, ..HyphenationConfig::default() };
+        assert_eq!(shown(&hyphenate(config).hyphenate_text("hyphenation")), "hy-phenation");
+
+        // words shorter than min_word are left whole
+        let config = HyphenationConfig { min_word: 6, left_min: 1, right_min: 1, ..HyphenationConfig::default() };
+        assert_eq!(shown(&hyphenate(config).hyphenate_text("hyphenation table")), "hy-phen-ation table");
+    }
+
+    #[test]
+    fn capitals_digits_and_addresses() {
+        let hyphenate = hyphenate(HyphenationConfig { min_word: 1, left_min: 1, right_min: 1, ..HyphenationConfig::default() });
+        let cases = [
+            ("Hyphenation, HYPHENATION", "Hy-phen-ation, HYPHENATION"),
+            ("hyPhenation", "hyPhenation"),
+            ("hyphenation2000 and 4hyphenation", "hyphenation2000 and 4hyphenation"),
+            ("see www.hyphenation.org, hyphenation@example.com", "see www.hyphenation.org, hyphenation@example.com"),
+            ("(hyphenation)", "(hy-phen-ation)"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(shown(&hyphenate.hyphenate_text(text)), expected, "{}", text);
+        }
+        // already hyphenated text is left as it is
+        let once = hyphenate.hyphenate_text("hyphenation");
+        assert_eq!(hyphenate.hyphenate_text(&once), once);
+    }
+
+    #[test]
+    fn code_html_and_headings_are_left_alone() {
+        let hyphenate = hyphenate(HyphenationConfig::default());
+        let markdown = "# Hyphenation\n\n\
+            Some hyphenation, `hyphenation` and <span class=\"hyphenation\">hyphenation</span>.\n\n\
+            ```\nhyphenation\n```\n";
+        let line_map = LineMap::new(markdown);
+        let chapter = Chapter { name: "test", path: "test.md", line_map: &line_map };
+
+        assert_eq!(
+            shown(&hyphenate.run(markdown, &chapter).unwrap()),
+            "# Hyphenation\n\n\
+            Some hy-phen-ation, `hyphenation` and <span class=\"hyphenation\">hy-phen-ation</span>.\n\n\
+            ```\nhyphenation\n```\n"
+        );
+    }
+}
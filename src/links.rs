@@ -0,0 +1,112 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use regex::Regex;
+
+use crate::diagnostics::Diagnostic;
+use crate::render::is_external;
+use crate::types::Page;
+
+// Check that every internal link in the generated XHTML points to a file in
+// the package and, if it has a fragment, to an element with that id. Broken
+// links in chapters are reported where they are in the markdown.
+pub fn check_links(dest_folder: &str, pages: &[Page]) -> usize {
+    let ops_path = Path::new(dest_folder).join("OPS");
+    let href_re = Regex::new(r#"<a\s[^>]*?href="([^"]*)""#).unwrap();
+    let mut ids_cache: HashMap<PathBuf, HashSet<String>> = HashMap::new();
+    let mut dangling = 0;
+
+    for file_path in xhtml_files(&ops_path) {
+        let content = match fs::read_to_string(&file_path) {
+            Ok(content) => content,
+            Err(err) => {
+                Diagnostic::error(format!("failed to read {}: {}", file_path.display(), err)).emit();
+                continue;
+            }
+        };
+
+        let display_name = file_path.strip_prefix(&ops_path).unwrap_or(&file_path).display().to_string();
+        let page = pages.iter().find(|page| display_name == format!("content/{}.xhtml", page.file));
+        // how many links with each href have been seen, to find the right one in the page
+        let mut seen: HashMap<&str, usize> = HashMap::new();
+
+        for caps in href_re.captures_iter(&content) {
+            let href = caps.get(1).unwrap().as_str();
+            if is_external(href) {
+                continue;
+            }
+
+            let occurrence = seen.entry(href).or_insert(0);
+            *occurrence += 1;
+            let written = href.replace("&amp;", "&");
+            let source_link = page.and_then(|page| {
+                page.links.iter().filter(|link| link.href == written).nth(*occurrence - 1).map(|link| (page, link))
+            });
+            let report = |message: String| {
+                let diagnostic = Diagnostic::warning(message);
+                match source_link {
+                    Some((page, link)) => diagnostic.at(&page.source, link.line, link.column).emit(),
+                    None => diagnostic.in_file(&display_name).emit(),
+                }
+            };
+
+            let (path, fragment) = match href.split_once('#') {
+                Some((path, fragment)) => (path, Some(fragment)),
+                None => (href, None),
+            };
+
+            let target = if path.is_empty() {
+                file_path.clone()
+            } else {
+                file_path.parent().unwrap_or(&ops_path).join(path)
+            };
+
+            if !target.is_file() {
+                report(format!("dangling link to {} (no such file)", href));
+                dangling += 1;
+                continue;
+            }
+
+            if let Some(fragment) = fragment.filter(|f| !f.is_empty()) {
+                let ids = ids_cache
+                    .entry(target.clone())
+                    .or_insert_with(|| collect_ids(&target));
+
+                if !ids.contains(fragment) {
+                    report(format!("dangling link to {} (no element with id \"{}\")", href, fragment));
+                    dangling += 1;
+                }
+            }
+        }
+    }
+
+    dangling
+}
+
+fn collect_ids(path: &Path) -> HashSet<String> {
+    let id_re = Regex::new(r#"\sid="([^"]*)""#).unwrap();
+    let content = fs::read_to_string(path).unwrap_or_default();
+
+    id_re
+        .captures_iter(&content)
+        .map(|caps| caps[1].to_string())
+        .collect()
+}
+
+pub fn xhtml_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            if path.is_dir() {
+                files.extend(xhtml_files(&path));
+            } else if path.extension() == Some("xhtml".as_ref()) {
+                files.push(path);
+            }
+        }
+    }
+
+    files.sort();
+    files
+}
@@ -1,24 +1,52 @@
 use std::env;
-use std::io;
-use std::fs::{self, DirEntry};
+use std::fs;
 use std::path::{Path, PathBuf};
 use compress::compress_epub;
-use pulldown_cmark::{html, Options, Parser};
+use pulldown_cmark::Parser;
 use uuid::Uuid;
 
 mod preprocess;
+mod prose;
+mod quotes;
 mod compress;
 mod types;
 mod util;
 mod epub;
-
-use types::{EpubInfo, Page};
+mod render;
+mod links;
+mod external;
+mod hyphenate;
+mod media;
+mod diff;
+mod audit;
+mod diagnostics;
+mod assets;
+mod theme;
+mod fonts;
+mod obfuscation;
+
+use audit::Audit;
+use preprocess::{Chapter, Pipeline};
+use diagnostics::{Diagnostic, MessageFormat};
+use types::{EpubInfo, FrontMatter, Page, Source, SourceLink};
 use util::*;
 use epub::*;
 
 fn main() {
-    // Get command-line arguments
-    let args: Vec<String> = env::args().collect();
+    // Get command-line arguments, taking out the options
+    let mut message_format = MessageFormat::Human;
+    let mut args: Vec<String> = Vec::new();
+    for arg in env::args() {
+        match arg.strip_prefix("--message-format=") {
+            Some("human") => message_format = MessageFormat::Human,
+            Some("json") => message_format = MessageFormat::Json,
+            Some(other) => {
+                eprintln!("Error: unknown message format \"{}\", expected human or json", other);
+                std::process::exit(1);
+            }
+            None => args.push(arg),
+        }
+    }
 
     // Determine the folder path
     let folder_path = if args.len() >= 2 {
@@ -28,6 +56,8 @@ fn main() {
         std::process::exit(1);
     };
 
+    diagnostics::init(message_format, folder_path);
+
     // Determine the YAML file path
     let yaml_path = PathBuf::from(folder_path).join("book.yaml");
 
@@ -35,24 +65,27 @@ fn main() {
     let mut epub_info: EpubInfo = match read_yaml_file(yaml_path.to_str().unwrap()) {
         Ok(epub_info) => epub_info,
         Err(err) => {
-            eprintln!("Error: Failed to read YAML file: {}", err);
+            let location = err.downcast_ref::<serde_yaml::Error>().and_then(|err| err.location());
+            let mut message = err.to_string();
+            // the location is shown on its own line
+            if let Some(index) = message.rfind(" at line ").filter(|_| location.is_some()) {
+                message.truncate(index);
+            }
+
+            let diagnostic = Diagnostic::error(format!("failed to read book.yaml: {}", message));
+            match location {
+                Some(location) => diagnostic.at("book.yaml", location.line(), location.column()).emit(),
+                None => diagnostic.in_file("book.yaml").emit(),
+            }
             std::process::exit(1);
         },
     };
 
 
-    epub_info.id = Some(Uuid::new_v4().hyphenated().to_string());
+    // the identifier is written as is in the OPF and NCX, and the fonts are
+    // obfuscated with it
+    epub_info.id = Some(format!("urn:uuid:{}", Uuid::new_v4().hyphenated()));
 
-    match check_font_files(&folder_path) {
-        Ok(fonts) => epub_info.fonts = fonts,
-        Err(e) => println!("Error checking for font files: {}", e),
-    }
-
-    match check_image_files(&folder_path) {
-        Ok(images) => epub_info.images = images,
-        Err(e) => println!("Error checking for image files: {}", e),
-    }
-    
     // Determine the EPUB name
     let epub_name = epub_info.name.clone();
 
@@ -67,73 +100,110 @@ fn main() {
 
     // Create the destination path
     let dest_path = PathBuf::from(dest_folder).join(epub_name);
+    if let Err(message) = clear_output(&dest_path, folder_path) {
+        Diagnostic::error(message).emit();
+        std::process::exit(1);
+    }
 
-    let raw_pages = process_markdown_files(&folder_path);
+    epub_info.assets = assets::scan_assets(folder_path, &epub_info.files, &dest_path);
+    // a theme template is filled in, not copied
+    if let Some(template) = &epub_info.theme.template {
+        epub_info.assets.retain(|asset| &asset.source != template);
+    }
+    epub_info.font_faces = fonts::read_fonts(folder_path, &epub_info.assets);
+    fonts::check_embedding(&epub_info);
+    epub_info.stylesheets = assets::book_stylesheets(epub_info.styles.as_deref(), &epub_info.assets);
 
-    let pages = rearrange_start_page(&epub_info, &raw_pages);
+    let mut audit = epub_info.audit.as_ref().map(|_| Audit::default());
 
-    create_epub(&dest_path, &epub_info, &pages);
+    let raw_pages = process_markdown_files(folder_path, &epub_info, audit.as_mut());
+
+    let pages = rearrange_start_page(&epub_info, &raw_pages);
 
     create_xhtml_files( &epub_info, &pages, dest_path.to_str().unwrap());
 
-    copy_files(folder_path, dest_path.to_str().unwrap());
+    create_builtin_css(dest_path.to_str().unwrap());
+
+    fonts::create_fonts_css(&epub_info, dest_path.to_str().unwrap());
+
+    theme::create_theme_css(&epub_info, folder_path, dest_path.to_str().unwrap());
 
     create_toc_xhtml(&epub_info, &pages, dest_path.to_str().unwrap());
 
+    // copy what the pages use before the manifest is written, so it lists
+    // exactly what made it into the book
+    let assets = std::mem::take(&mut epub_info.assets);
+    let assets = assets::referenced_assets(
+        folder_path,
+        dest_path.to_str().unwrap(),
+        assets,
+        &pages,
+        &generated_stylesheets(&epub_info),
+        epub_info.files.keep_unused,
+    );
+    epub_info.assets = assets::copy_assets(folder_path, dest_path.to_str().unwrap(), assets);
+
+    fonts::subset_fonts(&epub_info, folder_path, dest_path.to_str().unwrap());
+
+    let obfuscated = obfuscation::obfuscate_fonts(&epub_info, dest_path.to_str().unwrap());
+
+    create_epub(&dest_path, &epub_info, &pages);
+
+    let dangling = links::check_links(dest_path.to_str().unwrap(), &pages);
+    if dangling > 0 {
+        diagnostics::info(&format!("Found {} dangling link(s)", dangling));
+    }
+
+    if let (Some(audit), Some(config)) = (&audit, &epub_info.audit) {
+        audit.write_report(config, dest_path.to_str().unwrap());
+    }
+
+    fonts::report_fonts(&epub_info);
+
     create_mimetype_file(dest_path.to_str().unwrap());
 
-    compress_epub(dest_path.to_str().unwrap());
+    compress_epub(dest_path.to_str().unwrap(), &obfuscated);
+
+    diagnostics::print_summary();
+    if diagnostics::error_count() > 0 {
+        std::process::exit(1);
+    }
 }
 
-fn check_font_files(folder_path: &str) -> io::Result<Option<Vec<String>>> {
-    let entries: Vec<DirEntry> = fs::read_dir(Path::new(folder_path))?.collect::<Result<_, _>>()?;
-    let mut font_files = Vec::new();
-
-    for entry in entries {
-        let path = entry.path();
-        if path.is_file() {
-            if let Some(ext) = path.extension() {
-                if ext == "ttf" || ext == "otf" {
-                    if let Some(file_name) = path.file_name() {
-                        if let Some(file_name_str) = file_name.to_str() {
-                            font_files.push(String::from(file_name_str));
-                        }
-                    }
-                }
-            }
-        }
+// Remove what an earlier build left in the output folder, so no stale file
+// ends up in the book. A folder that mkepub did not make is left alone.
+fn clear_output(dest_path: &Path, book_folder: &str) -> Result<(), String> {
+    let Ok(mut entries) = fs::read_dir(dest_path) else {
+        return Ok(());
+    };
+    if entries.next().is_none() {
+        return Ok(());
     }
 
-    Ok(Some(font_files))
-}
+    let inside = match (fs::canonicalize(book_folder), fs::canonicalize(dest_path)) {
+        (Ok(book), Ok(dest)) => book.starts_with(dest),
+        _ => false,
+    };
+    if inside {
+        return Err(format!("the output folder {} holds the book itself, choose another destination", dest_path.display()));
+    }
 
-fn check_image_files(folder_path: &str) -> io::Result<Option<Vec<String>>> {
-    let entries: Vec<DirEntry> = fs::read_dir(Path::new(folder_path))?.collect::<Result<_, _>>()?;
-    let mut image_files = Vec::new();
-
-    for entry in entries {
-        let path = entry.path();
-        if path.is_file() {
-            if let Some(ext) = path.extension() {
-                if ext == "jpg" || ext == "png" {
-                    if let Some(file_name) = path.file_name() {
-                        if let Some(file_name_str) = file_name.to_str() {
-                            image_files.push(String::from(file_name_str));
-                        }
-                    }
-                }
-            }
-        }
+    let earlier_build = dest_path.join("mimetype").is_file() || dest_path.join("OPS").is_dir();
+    if !earlier_build {
+        return Err(format!(
+            "the output folder {} is not from an earlier build, move it away or choose another destination",
+            dest_path.display()
+        ));
     }
 
-    Ok(Some(image_files))
+    fs::remove_dir_all(dest_path).map_err(|err| format!("failed to clear the output folder {}: {}", dest_path.display(), err))
 }
 
 fn create_mimetype_file(dest_folder: &str) {
     let file_path = Path::new(dest_folder).join("mimetype");
 
     fs::write(&file_path, "application/epub+zip")
-        .unwrap_or_else(|err| eprintln!("Error writing mimetype file: {}", err));
+        .unwrap_or_else(|err| Diagnostic::error(format!("failed to write the mimetype file: {}", err)).emit());
 }
 
 fn rearrange_start_page(epub_info: &EpubInfo, pages: &[Page]) -> Vec<Page> {
@@ -145,10 +215,8 @@ fn rearrange_start_page(epub_info: &EpubInfo, pages: &[Page]) -> Vec<Page> {
                 let start_page_title = epub_info.start_title.as_deref().filter(|&title| !title.is_empty()).unwrap_or("Title page");
 
                 rearranged_pages.push(Page {
-                    name: page.name.clone(),
-                    file: page.file.clone(),
                     title: start_page_title.to_string(),
-                    body: page.body.clone(),
+                    ..page.clone()
                 });
             } else {
                 rearranged_pages.push(page.clone());
@@ -160,58 +228,29 @@ fn rearrange_start_page(epub_info: &EpubInfo, pages: &[Page]) -> Vec<Page> {
     rearranged_pages
 }
 
-fn copy_files(source_path: &str, dest_path: &str) -> io::Result<()> {
-    // create necessary directories
-    let ops_subdirs = ["css", "images", "fonts", "js"];
-    for subdir in ops_subdirs.iter() {
-        let dir = Path::new(dest_path).join(format!("OPS/{}", subdir));
-        fs::create_dir_all(&dir)?;
-    }
-
-    // helper function to copy files with a certain extension to a directory
-    fn copy_files_to_dir(dir: &Path, ext: &str, entries: &Vec<DirEntry>) -> io::Result<()> {
-        for entry in entries {
-            let path = entry.path();
-            if path.is_file() && path.extension() == Some(ext.as_ref()) {
-                let dest = dir.join(path.file_name().unwrap());
-                fs::copy(&path, &dest)?;
-            }
-        }
-        Ok(())
-    }
-
-    // get all entries in the source directory
-    let entries: Vec<DirEntry> = fs::read_dir(Path::new(source_path))?.collect::<Result<_, _>>()?;
-
-    // copy files to the appropriate directories
-    copy_files_to_dir(&Path::new(dest_path).join("OPS/css"), "css", &entries)?;
-    copy_files_to_dir(&Path::new(dest_path).join("OPS/images"), "png", &entries)?;
-    copy_files_to_dir(&Path::new(dest_path).join("OPS/images"), "jpg", &entries)?;
-    copy_files_to_dir(&Path::new(dest_path).join("OPS/fonts"), "ttf", &entries)?;
-    copy_files_to_dir(&Path::new(dest_path).join("OPS/fonts"), "otf", &entries)?;
-    copy_files_to_dir(&Path::new(dest_path).join("OPS/js"), "js", &entries)?;
-
-    Ok(())
-}
-
-fn render_markdown_to_page(source: &str) -> Page {
-    // Read the Markdown file content
-    let raw_content = fs::read_to_string(source).expect("Failed to read the Markdown file");
-
-    let markdown_content = preprocess::preprocess_markdown(&raw_content);
+fn render_markdown_to_page(source: &Source) -> Page {
+    let markdown_content = &source.content;
 
     // Parse the Markdown content
-    let parser = Parser::new_ext(&markdown_content, Options::all());
-
-    // Render the Markdown as XHTML
-    let mut xhtml_content = String::new();
-    html::push_html(&mut xhtml_content, parser);
+    let parser = Parser::new_ext(markdown_content, render::markdown_options()).into_offset_iter();
+
+    // Render the Markdown as XHTML, rewriting links to other chapters
+    let (xhtml_content, links) = render::render_events(parser);
+
+    // Keep where each link was written so a broken one can be reported there
+    let chapter = Chapter { name: &source.name, path: &source.path, line_map: &source.line_map };
+    let links = links
+        .into_iter()
+        .map(|(href, offset)| {
+            let (line, column) = chapter.location(markdown_content, offset);
+            SourceLink { href, line, column }
+        })
+        .collect();
 
     // Extract the title from the Markdown content
-    let title = extract_title(&markdown_content);
+    let title = extract_title(markdown_content);
 
-    // Get the file name without full path and extension
-    let name = get_file_name(source);
+    let name = source.name.clone();
 
     let file = sanitize_name(&name);
 
@@ -221,12 +260,21 @@ fn render_markdown_to_page(source: &str) -> Page {
         file,
         title,
         body: xhtml_content,
+        source: source.path.clone(),
+        links,
+        styles: Vec::new(),
     }
 }
 
-fn process_markdown_files(path: &str) -> Vec<Page> {
+fn process_markdown_files(path: &str, epub_info: &EpubInfo, mut audit: Option<&mut Audit>) -> Vec<Page> {
     // Read the directory contents
-    let dir_entries = fs::read_dir(path).expect("Failed to read directory");
+    let dir_entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(err) => {
+            Diagnostic::error(format!("failed to read the book folder {}: {}", path, err)).emit();
+            return Vec::new();
+        }
+    };
 
     // Collect and sort Markdown files by name
     let mut markdown_files: Vec<PathBuf> = dir_entries
@@ -242,15 +290,133 @@ fn process_markdown_files(path: &str) -> Vec<Page> {
         .collect();
     markdown_files.sort();
 
-    // Process each Markdown file
-    let mut results: Vec<Page> = Vec::new();
+    // Preprocess each Markdown file, then the book as a whole
+    let pipeline = Pipeline::from_config(epub_info, path);
+    let mut sources: Vec<Source> = Vec::new();
+    let mut styles: Vec<Vec<String>> = Vec::new();
     for file_path in markdown_files {
-        let file_path_str = file_path.to_string_lossy().to_string();
-        let result = render_markdown_to_page(&file_path_str);
-        results.push(result);
+        let relative = file_path.strip_prefix(path).unwrap_or(&file_path).to_string_lossy().to_string();
+
+        // a chapter that cannot be read is left out of the book
+        let raw_content = match fs::read_to_string(&file_path) {
+            Ok(content) => content,
+            Err(err) => {
+                Diagnostic::error(format!("failed to read chapter: {}", err)).in_file(&relative).emit();
+                continue;
+            }
+        };
+
+        let (front_matter, raw_content) = read_front_matter(&relative, raw_content);
+        styles.push(chapter_stylesheets(&relative, &front_matter, epub_info));
+
+        let name = get_file_name(&file_path.to_string_lossy());
+        let mut source = Source::new(name, relative, raw_content);
+        pipeline.run(&mut source, audit.as_deref_mut());
+
+        sources.push(source);
     }
+    external::run_book_preprocessors(epub_info, path, &mut sources, audit);
 
-    results
+    sources
+        .iter()
+        .zip(styles)
+        .map(|(source, styles)| Page { styles, ..render_markdown_to_page(source) })
+        .collect()
 }
 
+// Split the front matter off a chapter. Front matter that is not valid is
+// reported and ignored; either way its lines are left out of the chapter.
+fn read_front_matter(relative: &str, content: String) -> (FrontMatter, String) {
+    let Some((yaml, content)) = split_front_matter(&content) else {
+        return (FrontMatter::default(), content);
+    };
 
+    match serde_yaml::from_str::<Option<FrontMatter>>(&yaml) {
+        Ok(front_matter) => (front_matter.unwrap_or_default(), content),
+        Err(err) => {
+            let location = err.location();
+            let mut message = err.to_string();
+            if let Some(index) = message.rfind(" at line ").filter(|_| location.is_some()) {
+                message.truncate(index);
+            }
+
+            // the front matter starts on the second line
+            let diagnostic = Diagnostic::warning(format!("front matter ignored and left out of the chapter: {}", message));
+            match location {
+                Some(location) => diagnostic.at(relative, location.line() + 1, location.column()).emit(),
+                None => diagnostic.in_file(relative).emit(),
+            }
+            (FrontMatter::default(), content)
+        }
+    }
+}
+
+// The stylesheets a chapter asks for in its front matter, inside OPS
+fn chapter_stylesheets(relative: &str, front_matter: &FrontMatter, epub_info: &EpubInfo) -> Vec<String> {
+    front_matter
+        .styles
+        .iter()
+        .filter_map(|style| {
+            let href = assets::stylesheet_href(&epub_info.assets, style);
+            if href.is_none() {
+                Diagnostic::warning(format!("stylesheet {} is not in the book folder", style)).near(relative, style).emit();
+            }
+            // the book's stylesheets are linked already
+            href.filter(|href| !epub_info.stylesheets.contains(href))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use types::Asset;
+
+    #[test]
+    fn front_matter() {
+        let (front_matter, content) = read_front_matter("a.md", String::from("---\nstyles: [a.css]\n---\n# One\n"));
+        assert_eq!(front_matter.styles, ["a.css"]);
+        // the lines are kept, blank, so line numbers stay the same
+        assert_eq!(content, "\n\n\n# One\n");
+
+        let (front_matter, content) = read_front_matter("a.md", String::from("---\n---\n# One\n"));
+        assert!(front_matter.styles.is_empty());
+        assert_eq!(content, "\n\n# One\n");
+
+        // a chapter starting with a rule has no front matter
+        let (_, content) = read_front_matter("a.md", String::from("---\n# One\n"));
+        assert_eq!(content, "---\n# One\n");
+    }
+
+    #[test]
+    fn malformed_front_matter_is_left_out() {
+        for text in ["---\nstyles: [a.css\n---\n# One\n", "---\nstyles: 3\n---\n# One\n"] {
+            let (front_matter, content) = read_front_matter("a.md", text.to_string());
+            assert!(front_matter.styles.is_empty(), "{}", text);
+            assert_eq!(content, "\n\n\n# One\n", "{}", text);
+        }
+    }
+
+    #[test]
+    fn chapter_stylesheets_are_book_files() {
+        let mut epub_info: EpubInfo = serde_yaml::from_str("name: test\nauthor: Me\ntitle: Test\n").unwrap();
+        let css = media::from_extension(Path::new("a.css")).unwrap();
+        for (source, href) in [("book.css", "css/book.css"), ("styles/poem.css", "styles/poem.css")] {
+            epub_info.assets.push(Asset {
+                source: source.to_string(),
+                href: href.to_string(),
+                id: href.replace('/', "-"),
+                media_type: css,
+                fallback: None,
+            });
+        }
+        epub_info.stylesheets = vec![String::from("css/book.css")];
+
+        let front_matter = FrontMatter {
+            styles: vec![String::from("./styles/poem.css"), String::from("book.css"), String::from("missing.css")],
+        };
+        // the book's own stylesheets are linked already, and missing ones
+        // are reported
+        assert_eq!(chapter_stylesheets("a.md", &front_matter, &epub_info), ["styles/poem.css"]);
+    }
+}
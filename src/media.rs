@@ -0,0 +1,183 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Image,
+    Font,
+    Style,
+    Script,
+    Audio,
+    Video,
+}
+
+impl Kind {
+    // The folder under OPS the files go to
+    pub fn folder(self) -> &'static str {
+        match self {
+            Kind::Image => "images",
+            Kind::Font => "fonts",
+            Kind::Style => "css",
+            Kind::Script => "js",
+            Kind::Audio => "audio",
+            Kind::Video => "video",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct MediaType {
+    pub mime: &'static str,
+    pub kind: Kind,
+    pub extensions: &'static [&'static str],
+    // a core media type of EPUB 3, which every reading system supports
+    pub core: bool,
+    magic: fn(&[u8]) -> bool,
+}
+
+fn no_magic(_: &[u8]) -> bool {
+    false
+}
+
+// Every file type mkepub puts in a book. Sniffing tries them in this order.
+const MEDIA_TYPES: &[MediaType] = &[
+    MediaType { mime: "image/jpeg", kind: Kind::Image, extensions: &["jpg", "jpeg"], core: true, magic: |b| b.starts_with(&[0xFF, 0xD8, 0xFF]) },
+    MediaType { mime: "image/png", kind: Kind::Image, extensions: &["png"], core: true, magic: |b| b.starts_with(b"\x89PNG\r\n\x1a\n") },
+    MediaType { mime: "image/gif", kind: Kind::Image, extensions: &["gif"], core: true, magic: |b| b.starts_with(b"GIF87a") || b.starts_with(b"GIF89a") },
+    MediaType { mime: "image/webp", kind: Kind::Image, extensions: &["webp"], core: true, magic: |b| b.starts_with(b"RIFF") && b.get(8..12) == Some(b"WEBP") },
+    MediaType { mime: "image/svg+xml", kind: Kind::Image, extensions: &["svg"], core: true, magic: |b| String::from_utf8_lossy(b).contains("<svg") },
+    MediaType { mime: "image/bmp", kind: Kind::Image, extensions: &["bmp"], core: false, magic: |b| b.starts_with(b"BM") },
+    MediaType { mime: "image/tiff", kind: Kind::Image, extensions: &["tif", "tiff"], core: false, magic: |b| b.starts_with(b"II*\0") || b.starts_with(b"MM\0*") },
+    MediaType { mime: "font/otf", kind: Kind::Font, extensions: &["otf"], core: true, magic: |b| b.starts_with(b"OTTO") },
+    MediaType { mime: "font/ttf", kind: Kind::Font, extensions: &["ttf"], core: true, magic: |b| b.starts_with(&[0, 1, 0, 0]) || b.starts_with(b"true") },
+    MediaType { mime: "font/woff", kind: Kind::Font, extensions: &["woff"], core: true, magic: |b| b.starts_with(b"wOFF") },
+    MediaType { mime: "font/woff2", kind: Kind::Font, extensions: &["woff2"], core: true, magic: |b| b.starts_with(b"wOF2") },
+    MediaType { mime: "text/css", kind: Kind::Style, extensions: &["css"], core: true, magic: no_magic },
+    MediaType { mime: "application/javascript", kind: Kind::Script, extensions: &["js"], core: true, magic: no_magic },
+    MediaType { mime: "audio/mp4", kind: Kind::Audio, extensions: &["m4a", "aac"], core: true, magic: |b| b.get(4..8) == Some(b"ftyp") && b.get(8..11) == Some(b"M4A") },
+    MediaType { mime: "audio/ogg", kind: Kind::Audio, extensions: &["ogg", "oga", "opus"], core: true, magic: |b| b.starts_with(b"OggS") },
+    MediaType { mime: "audio/wav", kind: Kind::Audio, extensions: &["wav"], core: false, magic: |b| b.starts_with(b"RIFF") && b.get(8..12) == Some(b"WAVE") },
+    MediaType { mime: "audio/flac", kind: Kind::Audio, extensions: &["flac"], core: false, magic: |b| b.starts_with(b"fLaC") },
+    // EPUB has no core video types, but video needs no fallback either
+    MediaType { mime: "video/mp4", kind: Kind::Video, extensions: &["mp4", "m4v"], core: false, magic: |b| b.get(4..8) == Some(b"ftyp") },
+    MediaType { mime: "video/webm", kind: Kind::Video, extensions: &["webm"], core: false, magic: |b| b.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) },
+    // frame sync only, so it comes last
+    MediaType { mime: "audio/mpeg", kind: Kind::Audio, extensions: &["mp3"], core: true, magic: |b| b.starts_with(b"ID3") || (b.len() > 1 && b[0] == 0xFF && b[1] & 0xE0 == 0xE0) },
+];
+
+pub fn from_extension(path: &Path) -> Option<&'static MediaType> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    MEDIA_TYPES.iter().find(|media_type| media_type.extensions.contains(&extension.as_str()))
+}
+
+// The type given away by the first bytes of a file
+pub fn sniff(path: &Path) -> Option<&'static MediaType> {
+    let mut head = [0u8; 512];
+    let mut file = File::open(path).ok()?;
+    let length = file.read(&mut head).ok()?;
+
+    MEDIA_TYPES.iter().find(|media_type| (media_type.magic)(&head[..length]))
+}
+
+// What a file really is: its content decides, then its extension. Text
+// formats such as CSS can only be told by extension.
+pub fn identify(path: &Path) -> Option<&'static MediaType> {
+    let by_extension = from_extension(path)?;
+    match sniff(path) {
+        // an SVG is XML, which a stylesheet or script may mention too
+        Some(sniffed) if sniffed.mime == "image/svg+xml" && by_extension.mime != "image/svg+xml" => Some(by_extension),
+        Some(sniffed) => Some(sniffed),
+        None => Some(by_extension),
+    }
+}
+
+// Core types a non-core file can be converted to
+pub fn core_alternatives(kind: Kind) -> &'static str {
+    match kind {
+        Kind::Image => "JPEG, PNG, GIF, WebP or SVG",
+        Kind::Audio => "MP3, AAC (.m4a) or Ogg Opus",
+        Kind::Font => "TTF, OTF, WOFF or WOFF2",
+        Kind::Style | Kind::Script | Kind::Video => "a supported format",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    // Write files with the given names and first bytes to a folder of their own
+    fn write_files(test: &str, files: &[(&str, &[u8])]) -> PathBuf {
+        let folder = std::env::temp_dir().join(format!("mkepub-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        for (name, content) in files {
+            fs::write(folder.join(name), content).unwrap();
+        }
+        folder
+    }
+
+    #[test]
+    fn sniffs_content() {
+        let folder = write_files(
+            "sniff",
+            &[
+                ("a.jpg", b"\xFF\xD8\xFF\xE0\0\x10JFIF"),
+                ("a.png", b"\x89PNG\r\n\x1a\n\0\0"),
+                ("a.gif", b"GIF89a\x01\0"),
+                ("a.webp", b"RIFF\0\0\0\0WEBPVP8 "),
+                ("a.svg", b"<?xml version=\"1.0\"?>\n<svg xmlns=\"http://www.w3.org/2000/svg\"/>"),
+                ("a.otf", b"OTTO\0\x0b"),
+                ("a.ttf", b"\0\x01\0\0\0\x0b"),
+                ("a.woff2", b"wOF2\0\x01"),
+                ("a.wav", b"RIFF\0\0\0\0WAVEfmt "),
+                ("a.mp3", b"ID3\x04\0"),
+            ],
+        );
+
+        let cases = [
+            ("a.jpg", "image/jpeg"),
+            ("a.png", "image/png"),
+            ("a.gif", "image/gif"),
+            ("a.webp", "image/webp"),
+            ("a.svg", "image/svg+xml"),
+            ("a.otf", "font/otf"),
+            ("a.ttf", "font/ttf"),
+            ("a.woff2", "font/woff2"),
+            ("a.wav", "audio/wav"),
+            ("a.mp3", "audio/mpeg"),
+        ];
+        for (name, mime) in cases {
+            assert_eq!(sniff(&folder.join(name)).map(|media_type| media_type.mime), Some(mime), "{}", name);
+        }
+
+        let _ = fs::remove_dir_all(folder);
+    }
+
+    #[test]
+    fn content_wins_over_extension() {
+        let folder = write_files(
+            "identify",
+            &[
+                ("photo.png", b"\xFF\xD8\xFF\xE0\0\x10JFIF"),
+                ("Photo.JPG", b"\xFF\xD8\xFF\xE0\0\x10JFIF"),
+                ("style.css", b"/* <svg> icons */ body { margin: 0 }"),
+                ("script.js", b"let x = 1;"),
+                ("notes.txt", b"plain text"),
+                ("empty.png", b""),
+            ],
+        );
+
+        let mime = |name: &str| identify(&folder.join(name)).map(|media_type| media_type.mime);
+        assert_eq!(mime("photo.png"), Some("image/jpeg"));
+        assert_eq!(mime("Photo.JPG"), Some("image/jpeg"));
+        // text formats are known by extension, even when they mention <svg>
+        assert_eq!(mime("style.css"), Some("text/css"));
+        assert_eq!(mime("script.js"), Some("application/javascript"));
+        assert_eq!(mime("notes.txt"), None);
+        assert_eq!(mime("empty.png"), Some("image/png"));
+
+        let _ = fs::remove_dir_all(folder);
+    }
+}
@@ -0,0 +1,172 @@
+use std::fs;
+use std::path::Path;
+
+use crate::diagnostics::Diagnostic;
+use crate::epub::escape_href;
+use crate::types::{EpubInfo, Obfuscation};
+use crate::util::create_file;
+
+// Scramble the fonts the book asks for with the IDPF or Adobe algorithm,
+// keyed on the book's unique identifier, and list them in
+// META-INF/encryption.xml. Returns the paths of the fonts in the package,
+// which must be stored without compression.
+pub fn obfuscate_fonts(epub_info: &EpubInfo, dest_folder: &str) -> Vec<String> {
+    let config = &epub_info.fonts;
+    let identifier = epub_info.id.as_deref().unwrap_or_default();
+
+    for path in config.obfuscate_files.keys() {
+        if !epub_info.font_faces.iter().any(|font| &font.source == path) {
+            Diagnostic::warning(format!("{} is not one of the book's fonts", path)).in_config(path).emit();
+        }
+    }
+
+    let mut entries: Vec<(String, Obfuscation)> = Vec::new();
+    for font in &epub_info.font_faces {
+        let method = config.obfuscate_files.get(&font.source).copied().unwrap_or(config.obfuscate);
+        let key = match method {
+            Obfuscation::None => continue,
+            Obfuscation::Idpf => idpf_key(identifier),
+            Obfuscation::Adobe => match adobe_key(identifier) {
+                Some(key) => key,
+                None => {
+                    Diagnostic::error(format!(
+                        "{} cannot be obfuscated with Adobe's algorithm, which needs a UUID for the book's identifier",
+                        font.source
+                    ))
+                    .emit();
+                    continue;
+                }
+            },
+        };
+
+        let path = Path::new(dest_folder).join("OPS").join(&font.href);
+        let result = fs::read(&path).and_then(|mut data| {
+            scramble(&mut data, &key, method);
+            fs::write(&path, data)
+        });
+
+        match result {
+            Ok(()) => entries.push((format!("OPS/{}", font.href), method)),
+            Err(err) => Diagnostic::error(format!("failed to obfuscate {}: {}", font.href, err)).emit(),
+        }
+    }
+
+    if !entries.is_empty() {
+        let meta_inf = Path::new(dest_folder).join("META-INF");
+        let _ = fs::create_dir_all(&meta_inf);
+        create_file(&meta_inf.join("encryption.xml"), create_encryption_xml(&entries));
+    }
+
+    entries.into_iter().map(|(path, _)| path).collect()
+}
+
+// XOR the start of a font with the key: 1040 bytes for the IDPF algorithm,
+// 1024 for Adobe's. Scrambling twice gives the font back.
+fn scramble(data: &mut [u8], key: &[u8], method: Obfuscation) {
+    let length = match method {
+        Obfuscation::Adobe => 1024,
+        _ => 1040,
+    };
+    for (i, byte) in data.iter_mut().take(length).enumerate() {
+        *byte ^= key[i % key.len()];
+    }
+}
+
+// SHA-1 of the identifier with its white space taken out
+fn idpf_key(identifier: &str) -> Vec<u8> {
+    let identifier: String = identifier.chars().filter(|c| !matches!(c, ' ' | '\t' | '\r' | '\n')).collect();
+    sha1_smol::Sha1::from(identifier).digest().bytes().to_vec()
+}
+
+// The 16 bytes of the UUID in the identifier
+fn adobe_key(identifier: &str) -> Option<Vec<u8>> {
+    let hex: String = identifier
+        .trim()
+        .trim_start_matches("urn:uuid:")
+        .chars()
+        .filter(|&c| c != '-')
+        .collect();
+    if hex.len() != 32 {
+        return None;
+    }
+
+    (0..16).map(|i| u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()).collect()
+}
+
+fn create_encryption_xml(entries: &[(String, Obfuscation)]) -> String {
+    let data = entries
+        .iter()
+        .map(|(path, method)| {
+            let algorithm = match method {
+                Obfuscation::Adobe => "http://ns.adobe.com/pdf/enc#RC",
+                _ => "http://www.idpf.org/2008/embedding",
+            };
+            format!(
+                r#"  <enc:EncryptedData>
+    <enc:EncryptionMethod Algorithm="{}"/>
+    <enc:CipherData>
+      <enc:CipherReference URI="{}"/>
+    </enc:CipherData>
+  </enc:EncryptedData>"#,
+                algorithm,
+                escape_href(path)
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<encryption xmlns="urn:oasis:names:tc:opendocument:xmlns:container" xmlns:enc="http://www.w3.org/2001/04/xmlenc#">
+{}
+</encryption>
+"#,
+        data
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDENTIFIER: &str = "urn:uuid:0f0e0d0c-0b0a-0908-0706-050403020100";
+
+    #[test]
+    fn idpf_key_is_sha1_of_the_identifier() {
+        let key = idpf_key(IDENTIFIER);
+        assert_eq!(key, sha1_smol::Sha1::from(IDENTIFIER).digest().bytes().to_vec());
+        assert_eq!(key.len(), 20);
+        // white space is not part of the key
+        assert_eq!(idpf_key(" urn:uuid:0f0e0d0c-0b0a-0908-\n0706-050403020100\t"), key);
+        // the whole identifier counts, prefix and all
+        assert_ne!(idpf_key("0f0e0d0c-0b0a-0908-0706-050403020100"), key);
+    }
+
+    #[test]
+    fn adobe_key_is_the_uuid_bytes() {
+        let bytes: Vec<u8> = (0..16).rev().collect();
+        assert_eq!(adobe_key(IDENTIFIER), Some(bytes.clone()));
+        assert_eq!(adobe_key("0f0e0d0c-0b0a-0908-0706-050403020100"), Some(bytes));
+        assert_eq!(adobe_key("urn:isbn:9780000000000"), None);
+        assert_eq!(adobe_key("urn:uuid:0f0e0d0c-0b0a-0908-0706-05040302010g"), None);
+    }
+
+    #[test]
+    fn scrambled_lengths() {
+        let key = [0xffu8; 20];
+        for (method, length) in [(Obfuscation::Idpf, 1040), (Obfuscation::Adobe, 1024)] {
+            let mut data = vec![0u8; 2000];
+            scramble(&mut data, &key, method);
+            assert!(data[..length].iter().all(|&byte| byte == 0xff));
+            assert!(data[length..].iter().all(|&byte| byte == 0));
+
+            scramble(&mut data, &key, method);
+            assert!(data.iter().all(|&byte| byte == 0));
+        }
+
+        // a font shorter than the scrambled part is scrambled whole
+        let mut data = vec![0u8; 100];
+        scramble(&mut data, &[1, 2, 3], Obfuscation::Idpf);
+        assert_eq!(&data[..4], [1, 2, 3, 1]);
+    }
+}
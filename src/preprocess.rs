@@ -1,141 +1,771 @@
 use regex::Regex;
 
-pub fn preprocess_markdown(text: &str) -> String {
+use crate::prose::{code, code_blocks, map_prose, BlockKind};
+use crate::quotes::{curl_quotes, QuoteStyle};
+use crate::assets;
+use crate::audit::Audit;
+use crate::diagnostics::Diagnostic;
+use crate::diff::LineMap;
+use crate::external::External;
+use crate::hyphenate::Hyphenate;
+use crate::types::{
+    BreakConfig, BreakSpacing, BreakStyle, EllipsisSpacing, EpubInfo, Punctuation, ReplaceRule, Scope, Source,
+    TypographyConfig,
+};
 
-     // Remove spaces between quotes and punctuation
-    let mut contents = remove_spaces_between_quotes_and_punctuation(&text);
+// The rules run when book.yaml has no `preprocess.rules` list. The
+// ellipses, dashes, ranges, nbsp and hyphenate rules only run when listed.
+const DEFAULT_RULES: &[&str] = &[
+    "blocks",
+    "breaks",
+    "quote-spacing",
+    "quotes",
+    "punctuation",
+    "em-dashes",
+    "spaces",
+];
 
-    // Replace quotes and apostrophes with curly ones and fix punctuation placement
-    contents = replace_quotes(&contents);
-    contents = fix_punctuation(&contents);
+// Followed by a no-break space by the nbsp rule
+const DEFAULT_HONORIFICS: &[&str] = &[
+    "Mr.", "Mrs.", "Ms.", "Mx.", "Dr.", "Prof.", "St.", "Sr.", "Fr.", "Rev.", "Capt.", "Col.", "Gen.",
+    "Lt.", "Sgt.", "Hon.",
+];
 
-    // fix anomalies
-    contents = fix_em_anomaly(&contents);
-    contents = fix_tshirt_anomaly(&contents);
+// Preceded by a no-break space after a number by the nbsp rule
+const DEFAULT_UNITS: &[&str] = &[
+    "mm", "cm", "m", "km", "mg", "g", "kg", "t", "ml", "l", "s", "min", "h", "ms", "mph", "km/h",
+    "lb", "lbs", "oz", "ft", "yd", "mi", "%", "°C", "°F", "K", "Hz", "kHz", "MHz", "W", "kW", "V",
+    "kB", "MB", "GB", "TB",
+];
 
-    // Remove extra spaces
-    contents = remove_extra_spaces(&contents);
+// The chapter a preprocessor is working on
+pub struct Chapter<'a> {
+    pub name: &'a str,
+    pub path: &'a str,
+    // where the lines of the markdown handed to the preprocessor came from
+    pub line_map: &'a LineMap,
+}
 
-    let result = replace_breaks(&contents);
+impl Chapter<'_> {
+    // Line and column in the chapter file of a byte offset into `markdown`
+    pub fn location(&self, markdown: &str, offset: usize) -> (usize, usize) {
+        let before = &markdown[..offset];
+        let line_start = before.rfind('\n').map_or(0, |p| p + 1);
+        let line = before.matches('\n').count();
 
-    result
+        let column = before[line_start..].chars().count() + 1;
+
+        (self.line_map.source_line(line), self.line_map.source_column(line, column))
+    }
 }
 
-fn replace_breaks(text: &str) -> String {
-    text.replace("\n----\n", "\n\n&nbsp;\n\n<div class=\"center\">***</div>\n\n&nbsp;\n\n")
+// One named step of the preprocessing pipeline, given a chapter's markdown
+// and returning it rewritten. Passes that should only touch prose text run
+// their work through `map_prose`.
+pub trait Preprocessor {
+    // the name used in the `preprocess.rules` list of book.yaml
+    fn name(&self) -> &str;
+
+    fn run(&self, markdown: &str, chapter: &Chapter) -> Result<String, String>;
 }
 
-// pub fn xxpreprocess_markdown(text: &str) -> String {
+// Fenced divs and bracketed spans
+struct Blocks;
 
-//     // Remove spaces between quotes and punctuation
-//    let contents = remove_spaces_between_quotes_and_punctuation(&text);
+impl Preprocessor for Blocks {
+    fn name(&self) -> &str {
+        "blocks"
+    }
 
-//    // Replace quotes and apostrophes with curly ones and fix punctuation placement
-//    let replaced = replace_quotes_and_fix_punctuation(&contents);
+    fn run(&self, markdown: &str, _chapter: &Chapter) -> Result<String, String> {
+        Ok(replace_spans(&replace_blocks(markdown)))
+    }
+}
 
-//    // Remove extra spaces
-//    let no_extra_spaces = remove_extra_spaces(&replaced);
+// Scene breaks, with the image of the image style looked up among the
+// book's files
+struct Breaks {
+    config: BreakConfig,
+    // the image from the pages, such as ../images/break.png
+    src: Option<String>,
+}
 
-//    let result = replace_breaks(&no_extra_spaces);
+impl Breaks {
+    fn new(mut config: BreakConfig, epub_info: &EpubInfo) -> Breaks {
+        if let Some(ornament) = &epub_info.theme.ornament {
+            config.ornament = ornament.clone();
+        }
+        if config.style != BreakStyle::Image {
+            return Breaks { config, src: None };
+        }
 
-//    result
-// }
+        let href = match &config.image {
+            Some(image) => {
+                let href = assets::image_href(&epub_info.assets, image);
+                if href.is_none() {
+                    Diagnostic::warning(format!("scene break image {} is not in the book folder, using the text style", image))
+                        .in_config(image)
+                        .emit();
+                }
+                href
+            }
+            None => {
+                Diagnostic::warning("scene breaks have the image style but no image, using the text style")
+                    .in_config("image")
+                    .emit();
+                None
+            }
+        };
 
-// fn replace_breaks(text: &str) -> String {
-//    text.replace("\n----\n", "\n\n&nbsp;\n\n<div class=\"center\">***</div>\n\n&nbsp;\n\n")
-// }
+        match href {
+            Some(href) => Breaks { config, src: Some(format!("../{}", href)) },
+            None => Breaks { config: BreakConfig { style: BreakStyle::Text, ..config }, src: None },
+        }
+    }
+}
 
-fn remove_spaces_between_quotes_and_punctuation(text: &str) -> String {
-    let re = Regex::new(r#""\s+([.,!?…])"#).unwrap();
-    re.replace_all(text, "\"$1").into_owned()
+impl Preprocessor for Breaks {
+    fn name(&self) -> &str {
+        "breaks"
+    }
+
+    fn run(&self, markdown: &str, _chapter: &Chapter) -> Result<String, String> {
+        Ok(replace_breaks(markdown, &self.config, self.src.as_deref()))
+    }
 }
 
-fn replace_quotes(text: &str) -> String {
-    let mut output = String::new();
-    let mut inside_quote = false;
-    let mut inside_html_tag = false;
+struct Quotes(QuoteStyle);
 
-    for ch in text.chars() {
-        if ch == '<' {
-            inside_html_tag = true;
-        } else if ch == '>' {
-            inside_html_tag = false;
+impl Preprocessor for Quotes {
+    fn name(&self) -> &str {
+        "quotes"
+    }
+
+    fn run(&self, markdown: &str, chapter: &Chapter) -> Result<String, String> {
+        Ok(map_prose(markdown, chapter, |prose, _| curl_quotes(prose, &self.0)))
+    }
+}
+
+struct PunctuationPass(QuoteStyle);
+
+impl Preprocessor for PunctuationPass {
+    fn name(&self) -> &str {
+        "punctuation"
+    }
+
+    fn run(&self, markdown: &str, chapter: &Chapter) -> Result<String, String> {
+        Ok(map_prose(markdown, chapter, |prose, _| fix_punctuation(prose, &self.0)))
+    }
+}
+
+// Collapse spaces everywhere but in poetry, which keeps its indentation
+struct Spaces;
+
+impl Preprocessor for Spaces {
+    fn name(&self) -> &str {
+        "spaces"
+    }
+
+    fn run(&self, markdown: &str, chapter: &Chapter) -> Result<String, String> {
+        Ok(map_prose(markdown, chapter, |prose, kind| {
+            if kind == BlockKind::Verse {
+                prose.to_string()
+            } else {
+                remove_extra_spaces(prose)
+            }
+        }))
+    }
+}
+
+// `...` and `. . .` to an ellipsis character
+struct Ellipses {
+    re: Regex,
+    spacing: EllipsisSpacing,
+}
+
+impl Ellipses {
+    fn new(spacing: EllipsisSpacing) -> Ellipses {
+        Ellipses { re: Regex::new(r"( ?)\.(?: ?\.){2}").unwrap(), spacing }
+    }
+}
+
+impl Preprocessor for Ellipses {
+    fn name(&self) -> &str {
+        "ellipses"
+    }
+
+    fn run(&self, markdown: &str, chapter: &Chapter) -> Result<String, String> {
+        Ok(map_prose(markdown, chapter, |prose, _| {
+            self.re
+                .replace_all(prose, |caps: &regex::Captures| {
+                    let space = match (self.spacing, &caps[1]) {
+                        (_, "") | (EllipsisSpacing::Tight, _) => "",
+                        (EllipsisSpacing::Keep, space) => space,
+                        (EllipsisSpacing::Nbsp, _) => "\u{A0}",
+                    };
+                    format!("{}…", space)
+                })
+                .into_owned()
+        }))
+    }
+}
+
+// Numeric ranges such as 1939-1945 or pages 10-12 get an en dash. A chain
+// of numbers (2024-05-01, 555-123-4567) is left alone, and so is a number
+// stuck to a word (COVID-19).
+struct Ranges {
+    re: Regex,
+}
+
+impl Ranges {
+    fn new() -> Ranges {
+        Ranges { re: Regex::new(r"(\d+(?:[.,]\d+)?)-(\d+(?:[.,]\d+)?)").unwrap() }
+    }
+}
+
+impl Preprocessor for Ranges {
+    fn name(&self) -> &str {
+        "ranges"
+    }
+
+    fn run(&self, markdown: &str, chapter: &Chapter) -> Result<String, String> {
+        Ok(map_prose(markdown, chapter, |prose, _| {
+            let mut output = String::with_capacity(prose.len());
+            let mut position = 0;
+
+            for caps in self.re.captures_iter(prose) {
+                let whole = caps.get(0).unwrap();
+                let attached = |c: char| c.is_alphanumeric() || matches!(c, '-' | '–' | '/' | '.' | ',');
+                let before = prose[..whole.start()].chars().next_back();
+                let after = prose[whole.end()..].chars().next();
+                if before.is_some_and(attached) || after.is_some_and(|c| attached(c) && c != '.' && c != ',') {
+                    continue;
+                }
+
+                output.push_str(&prose[position..whole.start()]);
+                output.push_str(&format!("{}–{}", &caps[1], &caps[2]));
+                position = whole.end();
+            }
+            output.push_str(&prose[position..]);
+
+            output
+        }))
+    }
+}
+
+// No-break spaces after honorifics (Mr. Smith), between a number and its
+// unit (12 km) and inside initials (J. R. R. Tolkien)
+struct Nbsp {
+    honorifics: Option<Regex>,
+    units: Option<Regex>,
+    initials: Regex,
+}
+
+impl Nbsp {
+    fn new(config: &TypographyConfig) -> Nbsp {
+        let alternation = |words: &[String]| {
+            let mut words: Vec<String> = words.iter().map(|word| regex::escape(word)).collect();
+            // longest first so "Mrs." is not cut short by "Mr"
+            words.sort_by_key(|word| std::cmp::Reverse(word.len()));
+            words.join("|")
+        };
+        let list = |custom: &Option<Vec<String>>, default: &[&str]| -> Vec<String> {
+            match custom {
+                Some(words) => words.clone(),
+                None => default.iter().map(|word| word.to_string()).collect(),
+            }
+        };
+
+        let honorifics = list(&config.honorifics, DEFAULT_HONORIFICS);
+        let units = list(&config.units, DEFAULT_UNITS);
+
+        Nbsp {
+            honorifics: (!honorifics.is_empty())
+                .then(|| Regex::new(&format!(r"(^|[^\p{{L}}])({}) (\S)", alternation(&honorifics))).unwrap()),
+            units: (!units.is_empty())
+                .then(|| Regex::new(&format!(r"(\d) ({})($|[^\p{{L}}\p{{N}}])", alternation(&units))).unwrap()),
+            initials: Regex::new(r"(^|[^\p{L}])(\p{Lu}\.) (\p{Lu})").unwrap(),
+        }
+    }
+}
+
+impl Preprocessor for Nbsp {
+    fn name(&self) -> &str {
+        "nbsp"
+    }
+
+    fn run(&self, markdown: &str, chapter: &Chapter) -> Result<String, String> {
+        Ok(map_prose(markdown, chapter, |prose, _| {
+            let mut text = prose.to_string();
+
+            if let Some(re) = &self.honorifics {
+                text = re.replace_all(&text, "$1$2\u{A0}$3").into_owned();
+            }
+            if let Some(re) = &self.units {
+                text = re.replace_all(&text, "$1\u{A0}$2$3").into_owned();
+            }
+            // matches share a letter (J. R. R.), so repeat until all are found
+            loop {
+                let replaced = self.initials.replace_all(&text, "$1$2\u{A0}$3").into_owned();
+                if replaced == text {
+                    break;
+                }
+                text = replaced;
+            }
+
+            text
+        }))
+    }
+}
+
+// A simple pass over prose text
+struct ProsePass {
+    name: &'static str,
+    pass: fn(&str) -> String,
+}
+
+impl Preprocessor for ProsePass {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn run(&self, markdown: &str, chapter: &Chapter) -> Result<String, String> {
+        Ok(map_prose(markdown, chapter, |prose, _| (self.pass)(prose)))
+    }
+}
+
+// A find/replace rule from book.yaml
+struct Replace {
+    name: String,
+    re: Regex,
+    replacement: String,
+}
+
+impl Replace {
+    fn new(rule: &ReplaceRule) -> Result<Replace, regex::Error> {
+        let mut pattern = if rule.word {
+            format!(r"\b(?:{})\b", rule.find)
+        } else {
+            rule.find.clone()
+        };
+        if rule.ignore_case {
+            pattern = format!("(?i){}", pattern);
         }
 
-        if inside_html_tag {
-            output.push(ch);
-            continue;
+        Ok(Replace {
+            name: rule.name.clone(),
+            re: Regex::new(&pattern)?,
+            replacement: rule.replace.clone(),
+        })
+    }
+}
+
+impl Preprocessor for Replace {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn run(&self, markdown: &str, chapter: &Chapter) -> Result<String, String> {
+        Ok(map_prose(markdown, chapter, |prose, _| {
+            self.re.replace_all(prose, self.replacement.as_str()).into_owned()
+        }))
+    }
+}
+
+fn builtin(name: &str, epub_info: &EpubInfo, style: &QuoteStyle) -> Option<Box<dyn Preprocessor>> {
+    let preprocessor: Box<dyn Preprocessor> = match name {
+        "blocks" => Box::new(Blocks),
+        "breaks" => Box::new(Breaks::new(epub_info.breaks.clone(), epub_info)),
+        "quote-spacing" => Box::new(ProsePass { name: "quote-spacing", pass: remove_spaces_between_quotes_and_punctuation }),
+        "quotes" => Box::new(Quotes(style.clone())),
+        "punctuation" => Box::new(PunctuationPass(style.clone())),
+        "ellipses" => Box::new(Ellipses::new(epub_info.typography.ellipsis)),
+        "dashes" => Box::new(ProsePass { name: "dashes", pass: replace_dashes }),
+        "ranges" => Box::new(Ranges::new()),
+        "nbsp" => Box::new(Nbsp::new(&epub_info.typography)),
+        "em-dashes" => Box::new(ProsePass { name: "em-dashes", pass: fix_em_anomaly }),
+        "t-shirt" => Box::new(ProsePass { name: "t-shirt", pass: fix_tshirt_anomaly }),
+        "spaces" => Box::new(Spaces),
+        _ => return None,
+    };
+
+    Some(preprocessor)
+}
+
+// The preprocessors a book runs over each chapter, in order
+pub struct Pipeline {
+    preprocessors: Vec<Box<dyn Preprocessor>>,
+}
+
+impl Pipeline {
+    // Build the pipeline from the `preprocess` section of book.yaml. Custom
+    // and external rules run where `rules` lists them by name, or after the
+    // others if it does not mention them.
+    pub fn from_config(epub_info: &EpubInfo, book_folder: &str) -> Pipeline {
+        let config = &epub_info.preprocess;
+        let style = QuoteStyle::from_config(epub_info.language(), &epub_info.typography);
+
+        let default_rules: Vec<String> = DEFAULT_RULES.iter().map(|name| name.to_string()).collect();
+        let mut names = config.rules.clone().unwrap_or(default_rules);
+
+        let extra_names = config
+            .replace
+            .iter()
+            .map(|rule| &rule.name)
+            .chain(config.external.iter().filter(|ext| ext.scope == Scope::Chapter).map(|ext| &ext.name));
+        for name in extra_names {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
         }
 
-        match ch {
-            // Handle single quotes, assuming you want to replace them with curly ones
-            '\'' => output.push('’'), // Simplified; might need more logic for apostrophes vs quotes
-            // Handle double quotes
-            '"' | '“' | '”' => {
-                inside_quote = !inside_quote;
-                output.push(if inside_quote { '“' } else { '”' });
+        let mut preprocessors: Vec<Box<dyn Preprocessor>> = Vec::new();
+        for name in &names {
+            if let Some(rule) = config.replace.iter().find(|rule| &rule.name == name) {
+                match Replace::new(rule) {
+                    Ok(replace) => preprocessors.push(Box::new(replace)),
+                    Err(err) => Diagnostic::warning(format!("skipping preprocess rule \"{}\": {}", name, err))
+                        .in_config(&rule.find)
+                        .emit(),
+                }
+            } else if let Some(ext) = config.external.iter().find(|ext| &ext.name == name) {
+                if ext.scope == Scope::Chapter {
+                    preprocessors.push(Box::new(External::new(ext, epub_info, book_folder)));
+                }
+            } else if name == "hyphenate" {
+                // left out, with a warning, when there are no patterns
+                if let Some(hyphenate) = Hyphenate::new(epub_info, book_folder) {
+                    preprocessors.push(Box::new(hyphenate));
+                }
+            } else if let Some(preprocessor) = builtin(name, epub_info, &style) {
+                preprocessors.push(preprocessor);
+            } else {
+                Diagnostic::warning(format!("unknown preprocess rule \"{}\"", name)).in_config(name).emit();
             }
-            _ => output.push(ch),
         }
+
+        Pipeline { preprocessors }
     }
 
-    output
+    // Run every preprocessor over a chapter, recording what each one
+    // changed when the book asks for an audit
+    pub fn run(&self, source: &mut Source, mut audit: Option<&mut Audit>) {
+        for preprocessor in &self.preprocessors {
+            let chapter = Chapter { name: &source.name, path: &source.path, line_map: &source.line_map };
+
+            match preprocessor.run(&source.content, &chapter) {
+                Ok(output) => source.update(preprocessor.name(), output, audit.as_deref_mut()),
+                Err(err) => Diagnostic::warning(format!("preprocessor \"{}\" failed: {}", preprocessor.name(), err))
+                    .in_file(&source.path)
+                    .emit(),
+            }
+        }
+    }
 }
 
-fn xxreplace_quotes(text: &str) -> String {
-    let mut output = String::new();
-    let mut inside_quote = false;
-    let mut inside_html_tag = false;
+fn replace_breaks(text: &str, config: &BreakConfig, src: Option<&str>) -> String {
+    let marker: String = config.marker.split_whitespace().collect();
+    let lines: Vec<&str> = text.lines().collect();
 
-    for ch in text.chars() {
-        if ch == '<' {
-            inside_html_tag = true;
-        } else if ch == '>' {
-            inside_html_tag = false;
+    // find the lines that are scene break markers, skipping fenced code
+    let mut in_fence = false;
+    let is_break: Vec<bool> = lines
+        .iter()
+        .map(|line| {
+            let trimmed = line.trim();
+            if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+                in_fence = !in_fence;
+                return false;
+            }
+            !in_fence && !marker.is_empty() && trimmed.split_whitespace().collect::<String>() == marker
+        })
+        .collect();
+
+    let mut output: Vec<String> = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        if !is_break[i] {
+            output.push(line.to_string());
+            continue;
         }
 
-        if inside_html_tag {
-            output.push(ch);
+        // A break at the start or end of a chapter, or right after another
+        // break, would only leave an empty gap at a page boundary
+        let has_text_before = lines[..i]
+            .iter()
+            .rev()
+            .zip(is_break[..i].iter().rev())
+            .find(|(line, _)| !line.trim().is_empty())
+            .is_some_and(|(line, &brk)| !brk && !line.trim_start().starts_with('#'));
+        let has_text_after = lines[i + 1..]
+            .iter()
+            .zip(is_break[i + 1..].iter())
+            .any(|(line, &brk)| !brk && !line.trim().is_empty());
+
+        if has_text_before && has_text_after {
+            output.push(String::new());
+            output.push(render_break(config, src));
+            output.push(String::new());
+        }
+    }
+
+    let mut result = output.join("\n");
+    if text.ends_with('\n') {
+        result.push('\n');
+    }
+    result
+}
+
+// `::: epigraph` or `::: {.letter #id}` up to a closing `:::` becomes a
+// block element, with the markdown inside it rendered as usual
+fn replace_blocks(text: &str) -> String {
+    let code = code_blocks(text);
+    let mut output: Vec<String> = Vec::new();
+    let mut open_blocks: Vec<&'static str> = Vec::new();
+    let mut start = 0;
+
+    for line in text.split_inclusive('\n') {
+        let range = start..start + line.len();
+        start = range.end;
+        let line = line.trim_end_matches(['\n', '\r']);
+
+        let trimmed = line.trim();
+        let in_code = code.iter().any(|code| code.start < range.end && range.start < code.end);
+        if in_code || !trimmed.starts_with(":::") {
+            output.push(line.to_string());
             continue;
         }
 
+        let spec = trimmed.trim_start_matches(':').trim();
+        if spec.is_empty() {
+            match open_blocks.pop() {
+                Some(element) => {
+                    output.push(String::new());
+                    output.push(format!("</{}>", element));
+                    output.push(String::new());
+                }
+                None => output.push(line.to_string()),
+            }
+            continue;
+        }
+
+        let attributes = parse_attributes(spec.trim_start_matches('{').trim_end_matches('}'));
+        let element = block_element(&attributes);
+        open_blocks.push(element);
+
+        output.push(String::new());
+        output.push(format!("<{}{}>", element, render_attributes(&attributes)));
+        output.push(String::new());
+    }
+
+    // close anything left open at the end of the chapter
+    while let Some(element) = open_blocks.pop() {
+        output.push(String::new());
+        output.push(format!("</{}>", element));
+    }
+
+    let mut result = output.join("\n");
+    if text.ends_with('\n') {
+        result.push('\n');
+    }
+    result
+}
+
+// `[text]{.smallcaps}` becomes `<span class="smallcaps">text</span>`, except
+// in code
+fn replace_spans(text: &str) -> String {
+    let re = Regex::new(r"\[([^\[\]]+)\]\{([^{}]*)\}").unwrap();
+    let code = code(text);
+
+    re.replace_all(text, |caps: &regex::Captures| {
+        let span = caps.get(0).unwrap();
+        if code.iter().any(|code| code.start < span.end() && span.start() < code.end) {
+            return span.as_str().to_string();
+        }
+        let attributes = parse_attributes(&caps[2]);
+        format!("<span{}>{}</span>", render_attributes(&attributes), &caps[1])
+    })
+    .into_owned()
+}
+
+#[derive(Default)]
+struct Attributes {
+    id: Option<String>,
+    classes: Vec<String>,
+    epub_type: Option<String>,
+    other: Vec<(String, String)>,
+}
+
+// Parse `.class #id key=value key="some value"`; a bare word is a class
+fn parse_attributes(spec: &str) -> Attributes {
+    let mut attributes = Attributes::default();
+    let mut tokens: Vec<String> = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+
+    for ch in spec.chars() {
         match ch {
-            '\'' => {
-                // Handle single quotes logic
-                output.push('’'); // Simplified for the example
+            '"' => in_quotes = !in_quotes,
+            c if c.is_whitespace() && !in_quotes => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
             }
-            '"' => {
-                // Toggle inside_quote flag and handle double quotes
-                inside_quote = !inside_quote;
-                output.push(if inside_quote { '“' } else { '”' });
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+
+    for token in tokens {
+        if let Some(class) = token.strip_prefix('.') {
+            attributes.classes.push(class.to_string());
+        } else if let Some(id) = token.strip_prefix('#') {
+            attributes.id = Some(id.to_string());
+        } else if let Some((key, value)) = token.split_once('=') {
+            if key == "epub:type" {
+                attributes.epub_type = Some(value.to_string());
+            } else {
+                attributes.other.push((key.to_string(), value.to_string()));
             }
-            _ => output.push(ch),
+        } else {
+            attributes.classes.push(token);
         }
     }
 
-    output
+    if attributes.epub_type.is_none() {
+        attributes.epub_type = attributes
+            .classes
+            .iter()
+            .find_map(|class| default_epub_type(class))
+            .map(String::from);
+    }
+
+    attributes
 }
 
-fn fix_punctuation(text: &str) -> String {
+fn render_attributes(attributes: &Attributes) -> String {
     let mut output = String::new();
+
+    if let Some(id) = &attributes.id {
+        output.push_str(&format!(" id=\"{}\"", escape_attribute(id)));
+    }
+    if !attributes.classes.is_empty() {
+        output.push_str(&format!(" class=\"{}\"", escape_attribute(&attributes.classes.join(" "))));
+    }
+    if let Some(epub_type) = &attributes.epub_type {
+        output.push_str(&format!(" epub:type=\"{}\"", escape_attribute(epub_type)));
+    }
+    for (key, value) in &attributes.other {
+        output.push_str(&format!(" {}=\"{}\"", key, escape_attribute(value)));
+    }
+
+    output
+}
+
+fn escape_attribute(value: &str) -> String {
+    value.replace('&', "&amp;").replace('"', "&quot;").replace('<', "&lt;")
+}
+
+fn block_element(attributes: &Attributes) -> &'static str {
+    if attributes.classes.iter().any(|class| class == "quote" || class == "blockquote") {
+        "blockquote"
+    } else if attributes.classes.iter().any(|class| class == "note" || class == "aside") {
+        "aside"
+    } else {
+        "div"
+    }
+}
+
+fn default_epub_type(class: &str) -> Option<&'static str> {
+    match class {
+        "epigraph" => Some("epigraph"),
+        "dedication" => Some("dedication"),
+        "note" | "aside" => Some("note"),
+        "letter" => Some("z3998:letter"),
+        "poem" | "poetry" => Some("z3998:poem"),
+        "verse" => Some("z3998:verse"),
+        "song" | "lyrics" => Some("z3998:song"),
+        _ => None,
+    }
+}
+
+fn render_break(config: &BreakConfig, src: Option<&str>) -> String {
+    let epub_type = escape_attribute(&config.epub_type);
+    let ornament = escape_attribute(&config.ornament);
+    let element = match (config.style, src) {
+        (BreakStyle::Image, Some(src)) => format!(
+            "<div class=\"center scene-break\" epub:type=\"{}\"><img src=\"{}\" alt=\"{}\" /></div>",
+            epub_type,
+            escape_attribute(src),
+            ornament
+        ),
+        (BreakStyle::Rule, _) => format!("<hr class=\"scene-break\" epub:type=\"{}\" />", epub_type),
+        _ => format!("<div class=\"center scene-break\" epub:type=\"{}\">{}</div>", epub_type, ornament),
+    };
+
+    match config.spacing {
+        BreakSpacing::Nbsp => format!("&nbsp;\n\n{}\n\n&nbsp;", element),
+        BreakSpacing::Css => element,
+    }
+}
+
+fn remove_spaces_between_quotes_and_punctuation(text: &str) -> String {
+    let re = Regex::new(r#""\s+([.,!?…])"#).unwrap();
+    re.replace_all(text, "\"$1").into_owned()
+}
+
+// Place periods and commas inside closing quotes for American style, and
+// put the style's spacing inside quotes and before ; : ! ? for French
+fn fix_punctuation(text: &str, style: &QuoteStyle) -> String {
+    let mut output: Vec<char> = Vec::with_capacity(text.len());
     let chars: Vec<char> = text.chars().collect();
-    let length = chars.len();
-    let mut inside_quote = false;
+    let closing = [style.primary.1, style.secondary.1];
 
     let mut i = 0;
-    while i < length {
+    while i < chars.len() {
         let ch = chars[i];
 
-        if ch == '<' || ch == '>' {
-            output.push(ch);
-        } else if ch == '"' || ch == '“' || ch == '”' {
-            inside_quote = !inside_quote;
+        // quotes are curled by now, so the mark itself says whether it closes
+        if style.punctuation == Punctuation::American
+            && ch == style.primary.1
+            && i + 1 < chars.len()
+            && matches!(chars[i + 1], '.' | ',')
+        {
+            output.push(chars[i + 1]);
             output.push(ch);
+            i += 2;
+            continue;
+        }
+
+        if let Some(space) = style.spacing {
+            // high punctuation followed by a letter, digit or slash is part
+            // of something else, such as 10:30 or http://
+            let next = chars.get(i + 1).copied();
+            let ends_phrase = next.is_none_or(|c| !c.is_alphanumeric() && c != '/');
 
-            // Check if the next character is punctuation and we are closing a quote
-            if !inside_quote && i + 1 < length && is_punctuation(chars[i + 1]) {
-                i += 1; // Move past the quote
-                output.push(chars[i]); // Add the punctuation inside the quote
+            if (closing.contains(&ch) && ch != '’') || (matches!(ch, ';' | ':' | '!' | '?') && ends_phrase) {
+                // before a closing mark or high punctuation
+                if output.last() == Some(&' ') {
+                    output.pop();
+                }
+                // but none between the marks of ?! and the like
+                if output.last().is_some_and(|c| !c.is_whitespace() && *c != space && !matches!(c, '!' | '?')) {
+                    output.push(space);
+                }
+                output.push(ch);
+            } else if ch == style.primary.0 || ch == style.secondary.0 {
+                // after an opening mark
+                output.push(ch);
+                output.push(space);
+                while i + 1 < chars.len() && chars[i + 1] == ' ' {
+                    i += 1;
+                }
+                if i + 1 < chars.len() && chars[i + 1] == space {
+                    i += 1;
+                }
+            } else {
+                output.push(ch);
             }
         } else {
             output.push(ch);
@@ -144,7 +774,12 @@ fn fix_punctuation(text: &str) -> String {
         i += 1;
     }
 
-    output
+    output.into_iter().collect()
+}
+
+// `---` to an em dash and `--` to an en dash
+fn replace_dashes(text: &str) -> String {
+    text.replace("---", "—").replace("--", "–")
 }
 
 fn fix_em_anomaly(text: &str) -> String {
@@ -189,73 +824,309 @@ fn fix_tshirt_anomaly(text: &str) -> String {
     output
 }
 
-fn xxreplace_quotes_and_fix_punctuation(text: &str) -> String {
-    let mut output = String::new();
-    let mut inside_quote = false;
-    let mut inside_html_tag = false;
+// Collapse runs of spaces and tabs within each line
+fn remove_extra_spaces(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut previous_space = false;
 
-    let mut chars: Vec<char> = text.chars().collect();
-    let length = chars.len();
+    for ch in text.chars() {
+        if ch == ' ' || ch == '\t' {
+            if !previous_space {
+                output.push(' ');
+            }
+            previous_space = true;
+        } else {
+            output.push(ch);
+            previous_space = false;
+        }
+    }
 
-    for i in 0..length {
-        let ch = chars[i];
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
 
-        if ch == '<' {
-            inside_html_tag = true;
-        } else if ch == '>' {
-            inside_html_tag = false;
+    use crate::types::Asset;
+
+    fn style(language: &str) -> QuoteStyle {
+        QuoteStyle::from_config(language, &TypographyConfig::default())
+    }
+
+    #[test]
+    fn american_punctuation_goes_inside_quotes() {
+        assert_eq!(fix_punctuation("He said “no”. Then “yes”, twice.", &style("en-US")), "He said “no.” Then “yes,” twice.");
+        // the secondary closing mark is an apostrophe as often as not
+        assert_eq!(fix_punctuation("the boys’. Then", &style("en-US")), "the boys’. Then");
+        assert_eq!(fix_punctuation("“Why”? Why not.", &style("en-US")), "“Why”? Why not.");
+    }
+
+    #[test]
+    fn logical_punctuation_stays_put() {
+        assert_eq!(fix_punctuation("He said ‘no’. Then ‘yes’, twice.", &style("en-GB")), "He said ‘no’. Then ‘yes’, twice.");
+        assert_eq!(fix_punctuation("Wait ; what ? At 10:30!", &style("en-GB")), "Wait ; what ? At 10:30!");
+    }
+
+    #[test]
+    fn french_spacing() {
+        let nnbsp = '\u{202F}';
+        assert_eq!(
+            fix_punctuation("«Bonjour» dit-il. Quoi? Pourquoi ! Voici: rien ; enfin?!", &style("fr")),
+            format!(
+                "«{0}Bonjour{0}» dit-il. Quoi{0}? Pourquoi{0}! Voici{0}: rien{0}; enfin{0}?!",
+                nnbsp
+            )
+        );
+        // already spaced text is left as it is
+        let spaced = format!("«{0}Oui{0}»{0}!", nnbsp);
+        assert_eq!(fix_punctuation(&spaced, &style("fr")), spaced);
+    }
+
+    #[test]
+    fn french_spacing_skips_times_and_urls() {
+        let text = "Rendez-vous à 10:30 sur http://x.fr/a?b=1 ou Jean 3:16.";
+        assert_eq!(fix_punctuation(text, &style("fr")), text);
+    }
+
+    fn run(preprocessor: &dyn Preprocessor, markdown: &str) -> String {
+        let line_map = LineMap::new(markdown);
+        let chapter = Chapter { name: "test", path: "test.md", line_map: &line_map };
+        preprocessor.run(markdown, &chapter).unwrap()
+    }
+
+    #[test]
+    fn opt_in_rules_are_not_defaults() {
+        for name in ["ellipses", "dashes", "ranges", "nbsp", "hyphenate"] {
+            assert!(!DEFAULT_RULES.contains(&name), "{}", name);
         }
+    }
 
-        if inside_html_tag {
-            output.push(ch);
-            continue;
+    #[test]
+    fn ellipses() {
+        let text = "Wait... what . . . now `a...b`\n";
+        assert_eq!(run(&Ellipses::new(EllipsisSpacing::Keep), text), "Wait… what … now `a...b`\n");
+        assert_eq!(run(&Ellipses::new(EllipsisSpacing::Tight), text), "Wait… what… now `a...b`\n");
+        assert_eq!(run(&Ellipses::new(EllipsisSpacing::Nbsp), text), "Wait… what\u{A0}… now `a...b`\n");
+    }
+
+    #[test]
+    fn dashes() {
+        let dashes = ProsePass { name: "dashes", pass: replace_dashes };
+        assert_eq!(run(&dashes, "Wait---no -- yes, run `ls --all`\n"), "Wait—no – yes, run `ls --all`\n");
+    }
+
+    #[test]
+    fn ranges() {
+        let ranges = Ranges::new();
+        let cases = [
+            ("From 1939-1945.", "From 1939–1945."),
+            ("See pages 10-12, or 1.5-2.5 l", "See pages 10–12, or 1.5–2.5 l"),
+            ("On 2024-05-01 call 555-123-4567", "On 2024-05-01 call 555-123-4567"),
+            ("COVID-19 and 19-COVID and A4-5", "COVID-19 and 19-COVID and A4-5"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(run(&ranges, text), expected, "{}", text);
         }
+    }
 
-        match ch {
-            '\'' => {
-                if i > 0 && chars[i - 1].is_alphabetic() &&
-                    i < length - 1 && chars[i + 1].is_alphabetic() {
-                    output.push('’');
-                } else if inside_quote && i < length - 1 && is_punctuation(chars[i + 1]) {
-                    // If the next character is punctuation, move it before the quote
-                    output.push(chars[i + 1]);
-                    output.push('’');
-                    chars[i + 1] = ' '; // replace punctuation with space
-                    continue;
-                } else {
-                    output.push('‘');
-                }
-            }
-            '"' => {
-                inside_quote = !inside_quote;
-
-                if i < length - 1 && is_punctuation(chars[i + 1]) {
-                    // If the next character is punctuation, move it before the quote
-                    output.push(chars[i + 1]);
-                    output.push('”');
-                    chars[i + 1] = ' '; // replace punctuation with space
-                    continue;
-                } else {
-                    output.push('“');
-                }
-            }
-            _ => output.push(ch),
+    #[test]
+    fn nbsp() {
+        let nbsp = Nbsp::new(&TypographyConfig::default());
+        let cases = [
+            ("Dr. Who met Mrs. Hudson.", "Dr.\u{A0}Who met Mrs.\u{A0}Hudson."),
+            ("I said Dr. No to the Dr.", "I said Dr.\u{A0}No to the Dr."),
+            ("ADr. Who", "ADr. Who"),
+            ("5 m away, 5 min later, 5 more", "5\u{A0}m away, 5\u{A0}min later, 5 more"),
+            ("100 km/h and 20 %.", "100\u{A0}km/h and 20\u{A0}%."),
+            ("J. R. R. Tolkien", "J.\u{A0}R.\u{A0}R.\u{A0}Tolkien"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(run(&nbsp, text), expected, "{}", text);
         }
     }
 
-    output
-}
+    #[test]
+    fn nbsp_lists_replace_the_defaults() {
+        let config = TypographyConfig {
+            honorifics: Some(vec![String::from("Herr")]),
+            units: Some(Vec::new()),
+            ..TypographyConfig::default()
+        };
+        let nbsp = Nbsp::new(&config);
+        assert_eq!(run(&nbsp, "Herr Weber and Dr. Who walked 5 m"), "Herr\u{A0}Weber and Dr. Who walked 5 m");
+    }
 
-fn remove_extra_spaces(text: &str) -> String {
-    text.lines()
-        .map(|line| line.split_whitespace().collect::<Vec<&str>>().join(" "))
-        .collect::<Vec<String>>()
-        .join("\n")
-}
+    fn book(yaml: &str) -> EpubInfo {
+        serde_yaml::from_str(&format!("name: test\nauthor: Me\ntitle: Test\n{}", yaml)).unwrap()
+    }
+
+    #[test]
+    fn breaks_between_paragraphs_only() {
+        let breaks = Breaks::new(BreakConfig { spacing: BreakSpacing::Css, ..BreakConfig::default() }, &book(""));
+        let markup = "<div class=\"center scene-break\" epub:type=\"z3998:transition\">***</div>";
+
+        assert_eq!(run(&breaks, "One\n\n----\n\nTwo\n"), format!("One\n\n\n{}\n\n\nTwo\n", markup));
+        // not at the ends of a chapter, after a heading or twice in a row
+        assert_eq!(run(&breaks, "----\n\nOne\n\n----\n"), "\nOne\n\n");
+        assert_eq!(run(&breaks, "# One\n\n----\n\nTwo"), "# One\n\n\nTwo");
+        assert_eq!(run(&breaks, "One\n\n----\n\n- - - -\n\nTwo").matches("scene-break").count(), 1);
+        // nor in code
+        assert_eq!(run(&breaks, "One\n\n```\n----\n```\n\nTwo"), "One\n\n```\n----\n```\n\nTwo");
+    }
+
+    #[test]
+    fn break_styles() {
+        let config = BreakConfig { ornament: String::from("<*> & \"*\""), ..BreakConfig::default() };
+        assert_eq!(
+            render_break(&config, None),
+            "&nbsp;\n\n<div class=\"center scene-break\" epub:type=\"z3998:transition\">&lt;*> &amp; &quot;*&quot;</div>\n\n&nbsp;"
+        );
+
+        let config = BreakConfig { style: BreakStyle::Rule, spacing: BreakSpacing::Css, ..BreakConfig::default() };
+        assert_eq!(render_break(&config, None), "<hr class=\"scene-break\" epub:type=\"z3998:transition\" />");
+
+        let config = BreakConfig { style: BreakStyle::Image, spacing: BreakSpacing::Css, ..BreakConfig::default() };
+        assert_eq!(
+            render_break(&config, Some("../art/a \"b\".png")),
+            "<div class=\"center scene-break\" epub:type=\"z3998:transition\"><img src=\"../art/a &quot;b&quot;.png\" alt=\"***\" /></div>"
+        );
+    }
+
+    #[test]
+    fn break_images_are_book_files() {
+        let mut epub_info = book("");
+        let png = crate::media::from_extension(Path::new("a.png")).unwrap();
+        for (source, href) in [("fleuron.png", "images/fleuron.png"), ("art/fleuron.png", "art/fleuron.png")] {
+            epub_info.assets.push(Asset {
+                source: source.to_string(),
+                href: href.to_string(),
+                id: href.replace('/', "-"),
+                media_type: png,
+                fallback: None,
+            });
+        }
+        let image = |image: Option<&str>| BreakConfig {
+            style: BreakStyle::Image,
+            image: image.map(String::from),
+            ..BreakConfig::default()
+        };
+
+        let breaks = Breaks::new(image(Some("art/fleuron.png")), &epub_info);
+        assert_eq!(breaks.src.as_deref(), Some("../art/fleuron.png"));
+        let breaks = Breaks::new(image(Some("fleuron.png")), &epub_info);
+        assert_eq!(breaks.src.as_deref(), Some("../images/fleuron.png"));
+
+        // without an image the text style is used
+        for config in [image(None), image(Some("missing.png"))] {
+            let breaks = Breaks::new(config, &epub_info);
+            assert_eq!((breaks.config.style, breaks.src), (BreakStyle::Text, None));
+        }
+    }
+
+    #[test]
+    fn blocks_nest() {
+        assert_eq!(
+            replace_blocks("::: note\n::: {.letter #first}\nDear Sir\n:::\nSigned\n:::\n"),
+            "\n<aside class=\"note\" epub:type=\"note\">\n\n\n<div id=\"first\" class=\"letter\" epub:type=\"z3998:letter\">\n\nDear Sir\n\n</div>\n\nSigned\n\n</aside>\n\n"
+        );
+    }
+
+    #[test]
+    fn unclosed_blocks_close_at_the_end() {
+        assert_eq!(replace_blocks("::: epigraph\nWords"), "\n<div class=\"epigraph\" epub:type=\"epigraph\">\n\nWords\n\n</div>");
+        // a closer with nothing open is left as written
+        assert_eq!(replace_blocks("Words\n:::\n"), "Words\n:::\n");
+    }
+
+    #[test]
+    fn blocks_leave_code_alone() {
+        for text in ["```\n::: note\n```\n", "Text\n\n    ::: note\n    :::\n", "~~~md\n:::\n~~~\n"] {
+            assert_eq!(replace_blocks(text), text, "{}", text);
+        }
+        // an indented line continuing a paragraph is not code
+        assert!(replace_blocks("Text\n    ::: note\nMore\n").contains("<aside"));
+    }
+
+    #[test]
+    fn spans() {
+        assert_eq!(
+            replace_spans("A [Word]{.smallcaps} and [two words]{#x lang=fr}."),
+            "A <span class=\"smallcaps\">Word</span> and <span id=\"x\" lang=\"fr\">two words</span>."
+        );
+    }
+
+    #[test]
+    fn spans_leave_code_alone() {
+        let cases = [
+            "Use `[x]{.y}` here.",
+            "Use ``a ` [x]{.y}`` here.",
+            "Text\n\n    [x]{.y}\n",
+            "```\n[x]{.y}\n```\n",
+        ];
+        for text in cases {
+            assert_eq!(replace_spans(text), text, "{}", text);
+        }
+        assert_eq!(replace_spans("`code` then [x]{.y}"), "`code` then <span class=\"y\">x</span>");
+    }
+
+    fn pipeline_names(yaml: &str) -> Vec<String> {
+        let pipeline = Pipeline::from_config(&book(yaml), ".");
+        pipeline.preprocessors.iter().map(|preprocessor| preprocessor.name().to_string()).collect()
+    }
+
+    fn replace_rule(yaml: &str) -> Replace {
+        Replace::new(&serde_yaml::from_str(yaml).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn pipeline_order() {
+        assert_eq!(pipeline_names(""), DEFAULT_RULES);
+        assert_eq!(pipeline_names("preprocess:\n  rules: [quotes, blocks]\n"), ["quotes", "blocks"]);
+
+        // custom rules run where they are listed, or after the others
+        let replace = "  replace:\n    - {name: shirts, find: shirt, replace: T-shirt}\n    - {name: ok, find: okay, replace: OK}\n";
+        assert_eq!(
+            pipeline_names(&format!("preprocess:\n  rules: [blocks, ok, quotes]\n{}", replace)),
+            ["blocks", "ok", "quotes", "shirts"]
+        );
+        // book-scope programs are not part of the chapter pipeline
+        let external = "  external:\n    - {name: each, command: a}\n    - {name: all, command: b, scope: book}\n";
+        assert_eq!(pipeline_names(&format!("preprocess:\n  rules: [quotes]\n{}", external)), ["quotes", "each"]);
+    }
+
+    #[test]
+    fn unknown_and_broken_rules_are_skipped() {
+        assert_eq!(pipeline_names("preprocess:\n  rules: [quotes, smart-quotes, dashes]\n"), ["quotes", "dashes"]);
+        let broken = "preprocess:\n  rules: [quotes]\n  replace:\n    - {name: broken, find: \"(unclosed\", replace: x}\n";
+        assert_eq!(pipeline_names(broken), ["quotes"]);
+    }
+
+    #[test]
+    fn replace_rules_take_the_place_of_built_in_ones() {
+        let yaml = "preprocess:\n  rules: [dashes]\n  replace:\n    - {name: dashes, find: \"--\", replace: \"\u{2014}\"}\n";
+        let pipeline = Pipeline::from_config(&book(yaml), ".");
+        assert_eq!(pipeline.preprocessors.len(), 1);
+        assert_eq!(run(pipeline.preprocessors[0].as_ref(), "a -- b"), "a \u{2014} b");
+    }
+
+    #[test]
+    fn replace_rule_options() {
+        let plain = replace_rule("{name: cat, find: cat, replace: dog}");
+        assert_eq!(run(&plain, "cat concat Cat"), "dog condog Cat");
+
+        let word = replace_rule("{name: cat, find: cat, replace: dog, word: true}");
+        assert_eq!(run(&word, "cat concat cats cat."), "dog concat cats dog.");
+
+        let case = replace_rule("{name: cat, find: cat, replace: dog, ignore_case: true}");
+        assert_eq!(run(&case, "cat Cat CAT"), "dog dog dog");
+
+        // alternatives stay whole words, and groups can be used
+        let shirts = replace_rule("{name: shirts, find: \"t-?(shirts?)\", replace: \"T-$1\", word: true, ignore_case: true}");
+        assert_eq!(run(&shirts, "Tshirt, t-shirts, at-shirt"), "T-shirt, T-shirts, at-shirt");
 
-fn is_punctuation(ch: char) -> bool {
-    match ch {
-        ',' | '.' | '?' | '!' | ';' | ':' | '…' => true,
-        _ => false,
+        // code is left alone
+        assert_eq!(run(&plain, "a cat and `cat`"), "a dog and `cat`");
     }
 }
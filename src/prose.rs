@@ -0,0 +1,278 @@
+use std::ops::Range;
+
+use pulldown_cmark::{CodeBlockKind, Event, LinkType, Parser, Tag};
+
+use crate::diagnostics::Diagnostic;
+use crate::preprocess::Chapter;
+use crate::render::markdown_options;
+
+// Stand-ins for whatever separates two text nodes of the same block: markup
+// such as `*` that joins them, or whitespace such as a soft line break
+pub const JOIN_GAP: char = '\u{E000}';
+pub const SPACE_GAP: char = '\u{E001}';
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockKind {
+    Text,
+    Heading,
+    Verse,
+    Chat,
+}
+
+// The prose text of one block (paragraph, heading, table cell, poem...) as
+// byte ranges into the markdown source
+pub struct ProseBlock {
+    pub kind: BlockKind,
+    pub segments: Vec<Range<usize>>,
+}
+
+// Find every run of prose text in the markdown. Code spans, code blocks,
+// HTML, link destinations and autolinks never show up as prose.
+pub fn prose_blocks(markdown: &str) -> Vec<ProseBlock> {
+    let parser = Parser::new_ext(markdown, markdown_options()).into_offset_iter();
+    let mut blocks: Vec<ProseBlock> = Vec::new();
+    let mut current: Option<ProseBlock> = None;
+    let mut skip_depth = 0;
+
+    for (event, range) in parser {
+        match event {
+            Event::Start(tag) => match tag {
+                Tag::Emphasis | Tag::Strong | Tag::Strikethrough | Tag::Image(..) => {}
+                Tag::Link(link_type, ..) => {
+                    if matches!(link_type, LinkType::Autolink | LinkType::Email) {
+                        skip_depth += 1;
+                    }
+                }
+                Tag::CodeBlock(kind) => {
+                    flush(&mut current, &mut blocks);
+                    let block_kind = match &kind {
+                        CodeBlockKind::Fenced(info) => code_block_kind(info),
+                        CodeBlockKind::Indented => None,
+                    };
+                    match block_kind {
+                        Some(kind) => current = Some(ProseBlock { kind, segments: Vec::new() }),
+                        None => skip_depth += 1,
+                    }
+                }
+                Tag::Heading(..) => {
+                    flush(&mut current, &mut blocks);
+                    current = Some(ProseBlock { kind: BlockKind::Heading, segments: Vec::new() });
+                }
+                _ => {
+                    flush(&mut current, &mut blocks);
+                    current = Some(ProseBlock { kind: BlockKind::Text, segments: Vec::new() });
+                }
+            },
+            Event::End(tag) => match tag {
+                Tag::Emphasis | Tag::Strong | Tag::Strikethrough | Tag::Image(..) => {}
+                Tag::Link(link_type, ..) => {
+                    if matches!(link_type, LinkType::Autolink | LinkType::Email) {
+                        skip_depth -= 1;
+                    }
+                }
+                Tag::CodeBlock(kind) => {
+                    let is_prose = match &kind {
+                        CodeBlockKind::Fenced(info) => code_block_kind(info).is_some(),
+                        CodeBlockKind::Indented => false,
+                    };
+                    if !is_prose {
+                        skip_depth -= 1;
+                    }
+                    flush(&mut current, &mut blocks);
+                }
+                _ => flush(&mut current, &mut blocks),
+            },
+            Event::Text(text) if skip_depth == 0 => {
+                // text from escapes and entities differs from its source; leave it be
+                if markdown[range.clone()] != *text {
+                    continue;
+                }
+
+                let block = current.get_or_insert_with(|| ProseBlock {
+                    kind: BlockKind::Text,
+                    segments: Vec::new(),
+                });
+
+                match block.segments.last_mut() {
+                    Some(last) if last.end == range.start => last.end = range.end,
+                    _ => block.segments.push(range),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    flush(&mut current, &mut blocks);
+    blocks
+}
+
+// Run `transform` over the prose of every block and write the result back
+// into the markdown, leaving everything outside prose text exactly as written.
+// The transform sees a block's text nodes joined by JOIN_GAP or SPACE_GAP and
+// must keep those characters in place.
+pub fn map_prose<F>(markdown: &str, chapter: &Chapter, mut transform: F) -> String
+where
+    F: FnMut(&str, BlockKind) -> String,
+{
+    let mut replacements: Vec<(Range<usize>, String)> = Vec::new();
+
+    for block in prose_blocks(markdown) {
+        let joined = join_segments(markdown, &block.segments);
+        let transformed = transform(&joined, block.kind);
+        if transformed == joined {
+            continue;
+        }
+
+        let pieces: Vec<&str> = transformed.split([JOIN_GAP, SPACE_GAP]).collect();
+        if pieces.len() != block.segments.len() {
+            let (line, column) = chapter.location(markdown, block.segments[0].start);
+            Diagnostic::warning("typography pass changed the structure of a block, leaving it as written")
+                .at(chapter.path, line, column)
+                .emit();
+            continue;
+        }
+
+        for (segment, piece) in block.segments.iter().zip(pieces) {
+            if markdown[segment.clone()] != *piece {
+                replacements.push((segment.clone(), piece.to_string()));
+            }
+        }
+    }
+
+    replacements.sort_by_key(|(range, _)| range.start);
+
+    let mut output = String::with_capacity(markdown.len());
+    let mut position = 0;
+    for (range, text) in replacements {
+        output.push_str(&markdown[position..range.start]);
+        output.push_str(&text);
+        position = range.end;
+    }
+    output.push_str(&markdown[position..]);
+
+    output
+}
+
+// Byte ranges of the code blocks of the markdown, fenced or indented
+pub fn code_blocks(markdown: &str) -> Vec<Range<usize>> {
+    Parser::new_ext(markdown, markdown_options())
+        .into_offset_iter()
+        .filter(|(event, _)| matches!(event, Event::Start(Tag::CodeBlock(_))))
+        .map(|(_, range)| range)
+        .collect()
+}
+
+// Byte ranges of the code blocks and code spans of the markdown
+pub fn code(markdown: &str) -> Vec<Range<usize>> {
+    Parser::new_ext(markdown, markdown_options())
+        .into_offset_iter()
+        .filter(|(event, _)| matches!(event, Event::Start(Tag::CodeBlock(_)) | Event::Code(_)))
+        .map(|(_, range)| range)
+        .collect()
+}
+
+pub fn join_segments(markdown: &str, segments: &[Range<usize>]) -> String {
+    let mut joined = String::new();
+
+    for (i, segment) in segments.iter().enumerate() {
+        if i > 0 {
+            let gap = &markdown[segments[i - 1].end..segment.start];
+            joined.push(if gap.chars().any(char::is_whitespace) || gap.contains("&nbsp;") {
+                SPACE_GAP
+            } else {
+                JOIN_GAP
+            });
+        }
+        joined.push_str(&markdown[segment.clone()]);
+    }
+
+    joined
+}
+
+fn code_block_kind(info: &str) -> Option<BlockKind> {
+    match info.split_whitespace().next().unwrap_or_default() {
+        "chat" => Some(BlockKind::Chat),
+        "poem" | "verse" | "lyrics" => Some(BlockKind::Verse),
+        _ => None,
+    }
+}
+
+fn flush(current: &mut Option<ProseBlock>, blocks: &mut Vec<ProseBlock>) {
+    if let Some(block) = current.take() {
+        if !block.segments.is_empty() {
+            blocks.push(block);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::LineMap;
+
+    // Upper-case the prose of a chapter, leaving the gaps between text nodes
+    fn shout(markdown: &str) -> String {
+        let line_map = LineMap::new(markdown);
+        let chapter = Chapter { name: "test", path: "test.md", line_map: &line_map };
+        map_prose(markdown, &chapter, |prose, _| prose.to_uppercase())
+    }
+
+    #[test]
+    fn prose_is_changed() {
+        assert_eq!(shout("# Title\n\nSome *text* and **more**.\n"), "# TITLE\n\nSOME *TEXT* AND **MORE**.\n");
+        assert_eq!(shout("- one\n- two\n\n> quoted\n"), "- ONE\n- TWO\n\n> QUOTED\n");
+    }
+
+    #[test]
+    fn code_html_and_links_are_left_as_written() {
+        let cases = [
+            ("Run `ls -la` now.", "RUN `ls -la` NOW."),
+            ("```\nfenced code\n```\n", "```\nfenced code\n```\n"),
+            ("~~~rust\nlet a = 1;\n~~~\n", "~~~rust\nlet a = 1;\n~~~\n"),
+            ("Text\n\n    indented code\n", "TEXT\n\n    indented code\n"),
+            ("<div class=\"box\">\nraw html\n</div>\n", "<div class=\"box\">\nraw html\n</div>\n"),
+            ("A <span title=\"inline\">span</span>.", "A <span title=\"inline\">SPAN</span>."),
+            ("See [the docs](docs/page.md#part \"a title\").", "SEE [THE DOCS](docs/page.md#part \"a title\")."),
+            ("![a cover](images/cover.jpg)", "![A COVER](images/cover.jpg)"),
+            ("Go to <https://example.com/path> or <me@example.com>.", "GO TO <https://example.com/path> OR <me@example.com>."),
+            ("Escaped \\*stars\\* and &amp; entities", "ESCAPED \\*STARS\\* AND &amp; ENTITIES"),
+        ];
+        for (markdown, expected) in cases {
+            assert_eq!(shout(markdown), expected, "{}", markdown);
+        }
+    }
+
+    #[test]
+    fn block_kinds() {
+        let markdown = "# Title\n\nText\n\n```poem\nA line\n```\n\n```chat\nAlice: hi\n```\n\n```\ncode\n```\n";
+        let kinds: Vec<(BlockKind, String)> = prose_blocks(markdown)
+            .into_iter()
+            .map(|block| (block.kind, join_segments(markdown, &block.segments)))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (BlockKind::Heading, String::from("Title")),
+                (BlockKind::Text, String::from("Text")),
+                (BlockKind::Verse, String::from("A line\n")),
+                (BlockKind::Chat, String::from("Alice: hi\n")),
+            ]
+        );
+    }
+
+    #[test]
+    fn gaps_between_text_nodes() {
+        let markdown = "One *two*\nthree";
+        let block = &prose_blocks(markdown)[0];
+        assert_eq!(join_segments(markdown, &block.segments), format!("One {}two{}three", JOIN_GAP, SPACE_GAP));
+    }
+
+    #[test]
+    fn a_transform_losing_a_gap_leaves_the_block() {
+        let markdown = "One *two* three\n\nFour\n";
+        let line_map = LineMap::new(markdown);
+        let chapter = Chapter { name: "test", path: "test.md", line_map: &line_map };
+        let output = map_prose(markdown, &chapter, |prose, _| prose.replace([JOIN_GAP, SPACE_GAP], "").to_uppercase());
+        assert_eq!(output, "One *two* three\n\nFOUR\n");
+    }
+}
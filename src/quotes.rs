@@ -0,0 +1,430 @@
+use crate::diagnostics::Diagnostic;
+use crate::prose::{JOIN_GAP, SPACE_GAP};
+use crate::types::{Punctuation, TypographyConfig};
+
+// Words that start with an elided letter, so a leading ' is an apostrophe
+// ('em, 'tis, rock 'n' roll) rather than an opening quote
+const ELISIONS: &[&str] = &[
+    "alf", "ave", "bout", "cause", "cept", "cos", "coz", "cuz", "e", "em", "er", "ere", "fraid",
+    "gainst", "im", "kay", "n", "neath", "nuff", "ome", "ow", "round", "sup", "til", "tis", "tween",
+    "twas", "twere", "twill", "twixt", "twould", "un",
+];
+
+// Characters after which a quote mark opens a quotation
+const OPENERS: &[char] = &['(', '[', '{', '—', '–', '-', '/', '“', '‘', '«', '»', '„', '‚', '‹', '›'];
+
+// Quotation marks and punctuation rules for one language
+#[derive(Clone)]
+pub struct QuoteStyle {
+    pub primary: (char, char),
+    pub secondary: (char, char),
+    // space put inside quotation marks and before ; : ! ? (French)
+    pub spacing: Option<char>,
+    pub punctuation: Punctuation,
+}
+
+impl Default for QuoteStyle {
+    fn default() -> Self {
+        QuoteStyle {
+            primary: ('“', '”'),
+            secondary: ('‘', '’'),
+            spacing: None,
+            punctuation: Punctuation::American,
+        }
+    }
+}
+
+impl QuoteStyle {
+    // Pick the rule set named in `typography.quotes`, or the one for the book
+    // language, then apply any overrides from book.yaml
+    pub fn from_config(language: &str, config: &TypographyConfig) -> QuoteStyle {
+        let name = config.quotes.clone().unwrap_or_else(|| language.to_lowercase());
+
+        let mut style = match name.as_str() {
+            "en-gb" | "british" => QuoteStyle {
+                primary: ('‘', '’'),
+                secondary: ('“', '”'),
+                spacing: None,
+                punctuation: Punctuation::Logical,
+            },
+            "de-guillemets" | "de-ch" | "guillemets" => QuoteStyle {
+                primary: ('»', '«'),
+                secondary: ('›', '‹'),
+                spacing: None,
+                punctuation: Punctuation::Logical,
+            },
+            name if name.starts_with("de") || name.starts_with("sl") => QuoteStyle {
+                primary: ('„', '“'),
+                secondary: ('‚', '‘'),
+                spacing: None,
+                punctuation: Punctuation::Logical,
+            },
+            name if name.starts_with("fr") => QuoteStyle {
+                primary: ('«', '»'),
+                secondary: ('‹', '›'),
+                spacing: Some('\u{202F}'),
+                punctuation: Punctuation::Logical,
+            },
+            name if name.starts_with("en") => QuoteStyle::default(),
+            name => {
+                Diagnostic::warning(format!("unknown quote style \"{}\", using American English", name))
+                    .in_config(name)
+                    .emit();
+                QuoteStyle::default()
+            }
+        };
+
+        if let Some(marks) = config.primary.as_deref().and_then(mark_pair) {
+            style.primary = marks;
+        }
+        if let Some(marks) = config.secondary.as_deref().and_then(mark_pair) {
+            style.secondary = marks;
+        }
+        if let Some(spacing) = config.quote_spacing {
+            style.spacing = if spacing { Some('\u{202F}') } else { None };
+        }
+        if let Some(punctuation) = config.punctuation {
+            style.punctuation = punctuation;
+        }
+
+        style
+    }
+
+    fn marks(&self, level: Level) -> (char, char) {
+        match level {
+            Level::Primary => self.primary,
+            Level::Secondary => self.secondary,
+        }
+    }
+}
+
+fn mark_pair(marks: &str) -> Option<(char, char)> {
+    let chars: Vec<char> = marks.chars().filter(|c| !c.is_whitespace()).collect();
+    match chars[..] {
+        [open, close] => Some((open, close)),
+        _ => {
+            Diagnostic::warning(format!("quotation marks \"{}\" should be an opening and a closing mark", marks))
+                .in_config(marks)
+                .emit();
+            None
+        }
+    }
+}
+
+// Straight double quotes are the primary level, straight single quotes the
+// secondary one
+#[derive(Clone, Copy, PartialEq)]
+enum Level {
+    Primary,
+    Secondary,
+}
+
+// An open quotation: the level the author typed and the one it is shown as
+struct Open {
+    typed: Level,
+    shown: Level,
+}
+
+// Curl the straight quotes and apostrophes of one block of prose. Context
+// tells apart apostrophes (don't, dogs'), elisions ('90s, 'em), opening and
+// closing quotes; a quotation typed inside another one of the same kind
+// ("she said "no"") is shown with the other kind of marks.
+//
+// Curly quotes already in the text are kept as written and only update
+// which quotations are open, so a stray or unbalanced mark never flips the
+// ones after it, and running the pass again changes nothing.
+pub fn curl_quotes(text: &str, style: &QuoteStyle) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut output: Vec<char> = Vec::with_capacity(chars.len());
+    let mut open: Vec<Open> = Vec::new();
+
+    for i in 0..chars.len() {
+        let ch = chars[i];
+        let prev = previous_char(&output);
+        let next = next_char(&chars, i);
+
+        let after_opener = prev.is_none_or(|c| c.is_whitespace() || OPENERS.contains(&c));
+        let before_space = next.is_none_or(|c| c.is_whitespace());
+
+        match ch {
+            '"' => {
+                let opening = match (after_opener, before_space) {
+                    (true, false) => true,
+                    (false, true) => false,
+                    // squeezed between two characters, as in `"Hi,"she`
+                    (false, false) => !next.is_some_and(is_closing_punctuation) && !is_open(&open, Level::Primary),
+                    // standing on its own
+                    (true, true) => !is_open(&open, Level::Primary),
+                };
+
+                if opening {
+                    let shown = nested_level(&open, Level::Primary);
+                    open.push(Open { typed: Level::Primary, shown });
+                    output.push(style.marks(shown).0);
+                } else {
+                    let shown = close(&mut open, Level::Primary).unwrap_or(Level::Primary);
+                    output.push(style.marks(shown).1);
+                }
+            }
+            '\'' => {
+                let prev_alnum = prev.is_some_and(char::is_alphanumeric);
+                let next_alnum = next.is_some_and(char::is_alphanumeric);
+
+                if prev_alnum && next_alnum {
+                    // don't, o'clock
+                    output.push('’');
+                } else if after_opener && next.is_some_and(|c| c.is_ascii_digit()) {
+                    // '90s
+                    output.push('’');
+                } else if after_opener && is_elision(&chars[i + 1..]) {
+                    // 'em, 'tis
+                    output.push('’');
+                } else if after_opener && !before_space {
+                    let shown = nested_level(&open, Level::Secondary);
+                    open.push(Open { typed: Level::Secondary, shown });
+                    output.push(style.marks(shown).0);
+                } else if is_open(&open, Level::Secondary) {
+                    let shown = close(&mut open, Level::Secondary).unwrap_or(Level::Secondary);
+                    output.push(style.marks(shown).1);
+                } else {
+                    // dogs', goin'
+                    output.push('’');
+                }
+            }
+            _ => {
+                if let Some(level) = curly_level(style, ch) {
+                    track_curly(style, ch, level, &mut open, after_opener, before_space, next);
+                }
+                output.push(ch);
+            }
+        }
+    }
+
+    output.into_iter().collect()
+}
+
+// The quotation level a curly mark of the style belongs to
+fn curly_level(style: &QuoteStyle, ch: char) -> Option<Level> {
+    if ch == style.primary.0 || ch == style.primary.1 {
+        Some(Level::Primary)
+    } else if ch == style.secondary.0 || ch == style.secondary.1 {
+        Some(Level::Secondary)
+    } else {
+        None
+    }
+}
+
+// Update the open quotations for a curly mark already in the text. A mark
+// the context clearly contradicts (”Hi,“) was typeset the wrong way round;
+// it is kept, but tracked for what it does.
+fn track_curly(
+    style: &QuoteStyle,
+    ch: char,
+    level: Level,
+    open: &mut Vec<Open>,
+    after_opener: bool,
+    before_space: bool,
+    next: Option<char>,
+) {
+    let (opening_mark, _) = style.marks(level);
+
+    if ch == '’' {
+        // also the apostrophe, so it only ever closes
+        if !next.is_some_and(char::is_alphanumeric) && is_open(open, level) {
+            close(open, level);
+        }
+    } else if ch == opening_mark {
+        if !after_opener && before_space {
+            close(open, level);
+        } else {
+            open.push(Open { typed: level, shown: level });
+        }
+    } else if after_opener && !before_space {
+        open.push(Open { typed: level, shown: level });
+    } else {
+        close(open, level);
+    }
+}
+
+// A quotation opened directly inside one shown with the same marks
+// alternates to the other kind
+fn nested_level(open: &[Open], typed: Level) -> Level {
+    match open.last() {
+        Some(outer) if outer.shown == typed => match typed {
+            Level::Primary => Level::Secondary,
+            Level::Secondary => Level::Primary,
+        },
+        _ => typed,
+    }
+}
+
+fn is_open(open: &[Open], typed: Level) -> bool {
+    open.iter().any(|quote| quote.typed == typed)
+}
+
+// Close the innermost quotation typed at `typed`, along with anything left
+// open inside it, and return how it was shown
+fn close(open: &mut Vec<Open>, typed: Level) -> Option<Level> {
+    let position = open.iter().rposition(|quote| quote.typed == typed)?;
+    let shown = open[position].shown;
+    open.truncate(position);
+    Some(shown)
+}
+
+fn is_elision(rest: &[char]) -> bool {
+    let word: String = rest
+        .iter()
+        .take_while(|c| c.is_alphabetic())
+        .collect::<String>()
+        .to_lowercase();
+
+    ELISIONS.contains(&word.as_str())
+}
+
+fn is_closing_punctuation(ch: char) -> bool {
+    matches!(ch, ',' | '.' | '?' | '!' | ';' | ':' | '…' | ')' | ']' | '—' | '–')
+}
+
+// The character before the current one, looking through markup between
+// text nodes; a whitespace gap reads as a space
+fn previous_char(output: &[char]) -> Option<char> {
+    output
+        .iter()
+        .rev()
+        .find(|&&c| c != JOIN_GAP)
+        .map(|&c| if c == SPACE_GAP { ' ' } else { c })
+}
+
+fn next_char(chars: &[char], i: usize) -> Option<char> {
+    chars[i + 1..]
+        .iter()
+        .find(|&&c| c != JOIN_GAP)
+        .map(|&c| if c == SPACE_GAP { ' ' } else { c })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{curl_quotes, QuoteStyle};
+    use crate::types::TypographyConfig;
+    use crate::diff::LineMap;
+    use crate::preprocess::Chapter;
+    use crate::prose::map_prose;
+
+    // Regression corpus, mostly sentences from the changeover/ chapters typed
+    // with straight quotes, next to how they should come out
+    const CORPUS: &[(&str, &str)] = &[
+        (
+            r#""I slept with a prostitute." My stomach churned."#,
+            "“I slept with a prostitute.” My stomach churned.",
+        ),
+        (
+            r#"Lexi's scent still lingered. Never could I imagine I'd stoop so low."#,
+            "Lexi’s scent still lingered. Never could I imagine I’d stoop so low.",
+        ),
+        (
+            r#""To be honest, you're not what I expected," I confessed to Dan. "'I thought you'd have an office where I'd recline on a couch.'" His laughter was genuine."#,
+            "“To be honest, you’re not what I expected,” I confessed to Dan. “‘I thought you’d have an office where I’d recline on a couch.’” His laughter was genuine.",
+        ),
+        (
+            r#"the same way as 'one' would reflect the absolute ugliness. You won't find those either.""#,
+            "the same way as ‘one’ would reflect the absolute ugliness. You won’t find those either.”",
+        ),
+        (
+            r#""I'm afraid she would reject me," I admitted."#,
+            "“I’m afraid she would reject me,” I admitted.",
+        ),
+        (
+            r#""She said 'no' to me," he said."#,
+            "“She said ‘no’ to me,” he said.",
+        ),
+        (
+            r#"He said "she told me "no" twice" and left."#,
+            "He said “she told me ‘no’ twice” and left.",
+        ),
+        (
+            r#"Back in the '90s we loved 'em all."#,
+            "Back in the ’90s we loved ’em all.",
+        ),
+        (
+            r#"'Tis the season for rock 'n' roll."#,
+            "’Tis the season for rock ’n’ roll.",
+        ),
+        (
+            r#"The girls' dresses and the boss' car were goin' nowhere."#,
+            "The girls’ dresses and the boss’ car were goin’ nowhere.",
+        ),
+        (
+            r#""Wait—" she began. "Don't—""#,
+            "“Wait—” she began. “Don’t—”",
+        ),
+        (
+            r#"(“Already curled,” she said, 'but these aren't.')"#,
+            "(“Already curled,” she said, ‘but these aren’t.’)",
+        ),
+        (
+            "\"Hello,\u{E000}\" she said.",
+            "“Hello,\u{E000}” she said.",
+        ),
+    ];
+
+    #[test]
+    fn corpus() {
+        for (input, expected) in CORPUS {
+            assert_eq!(curl_quotes(input, &QuoteStyle::default()), *expected, "input: {}", input);
+        }
+    }
+
+    #[test]
+    fn idempotent() {
+        for (input, _) in CORPUS {
+            let once = curl_quotes(input, &QuoteStyle::default());
+            assert_eq!(curl_quotes(&once, &QuoteStyle::default()), once, "input: {}", input);
+        }
+    }
+
+    #[test]
+    fn pre_curled_quotes_are_kept() {
+        let text = "”Inverted,“ she said. “Fine,” he said, \"and 'this' too.\"";
+        assert_eq!(curl_quotes(text, &QuoteStyle::default()), "”Inverted,“ she said. “Fine,” he said, “and ‘this’ too.”");
+    }
+
+    #[test]
+    fn quote_state_resets_per_paragraph() {
+        let markdown = "\"It started in the spring. The rain didn't stop\n\
+            for weeks.\n\n\
+            \"Then came the floods,\" he said. \"Nobody was ready.\n\n\
+            A stray 12\" pipe. \"Still fine,\" she said.\n";
+
+        let expected = "“It started in the spring. The rain didn’t stop\n\
+            for weeks.\n\n\
+            “Then came the floods,” he said. “Nobody was ready.\n\n\
+            A stray 12” pipe. “Still fine,” she said.\n";
+
+        let line_map = LineMap::new(markdown);
+        let chapter = Chapter { name: "floods", path: "floods.md", line_map: &line_map };
+
+        let curled = map_prose(markdown, &chapter, |text, _| curl_quotes(text, &QuoteStyle::default()));
+        assert_eq!(curled, expected);
+        assert_eq!(map_prose(&curled, &chapter, |text, _| curl_quotes(text, &QuoteStyle::default())), curled);
+    }
+
+    #[test]
+    fn locale_styles() {
+        let text = r#""She said 'no' to me," he said. It's done."#;
+        let cases = [
+            ("en-US", "“She said ‘no’ to me,” he said. It’s done."),
+            ("en-GB", "‘She said “no” to me,’ he said. It’s done."),
+            ("de", "„She said ‚no‘ to me,“ he said. It’s done."),
+            ("de-guillemets", "»She said ›no‹ to me,« he said. It’s done."),
+            ("fr", "«She said ‹no› to me,» he said. It’s done."),
+            ("sl", "„She said ‚no‘ to me,“ he said. It’s done."),
+        ];
+
+        for (language, expected) in cases {
+            let style = QuoteStyle::from_config(language, &TypographyConfig::default());
+            let curled = curl_quotes(text, &style);
+            assert_eq!(curled, expected, "language: {}", language);
+            assert_eq!(curl_quotes(&curled, &style), curled, "language: {}", language);
+        }
+    }
+}
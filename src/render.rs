@@ -0,0 +1,474 @@
+use std::collections::HashSet;
+use std::ops::Range;
+
+use pulldown_cmark::{html, CodeBlockKind, CowStr, Event, Options, Parser, Tag};
+
+use crate::util::{get_file_name, percent_decode, sanitize_name};
+
+// Markdown extensions used for every chapter. Smart punctuation is left to
+// the preprocessing rules so each change can be configured and audited.
+pub fn markdown_options() -> Options {
+    let mut options = Options::all();
+    options.remove(Options::ENABLE_SMART_PUNCTUATION);
+    options
+}
+
+// Render a chapter to XHTML. Also returns every link and image in it, as
+// written to the XHTML, with its byte offset in the markdown.
+pub fn render_events<'a>(events: impl Iterator<Item = (Event<'a>, Range<usize>)>) -> (String, Vec<(String, usize)>) {
+    let (events, offsets): (Vec<Event<'a>>, Vec<Range<usize>>) = events.unzip();
+    // explicit ids are taken first, so a generated id never repeats one
+    let mut used_ids: HashSet<String> = events
+        .iter()
+        .filter_map(|event| match event {
+            Event::Start(Tag::Heading(_, Some(id), _)) => Some(id.to_string()),
+            _ => None,
+        })
+        .collect();
+    let mut output: Vec<Event<'a>> = Vec::with_capacity(events.len());
+    let mut links: Vec<(String, usize)> = Vec::new();
+
+    let mut index = 0;
+    while index < events.len() {
+        let event = &events[index];
+        let offset = offsets[index].start;
+        index += 1;
+
+        match event {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) if block_kind(info) == "chat" => {
+                let (text, consumed) = code_block_text(&events[index..]);
+                index += consumed;
+                output.push(Event::Html(CowStr::from(render_chat(&text, info))));
+            }
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) if is_verse(info) => {
+                let (text, consumed) = code_block_text(&events[index..]);
+                index += consumed;
+                output.push(Event::Html(CowStr::from(render_verse(&text, info))));
+            }
+            Event::Start(Tag::Heading(level, id, classes)) => {
+                // Give every heading an anchor so other chapters can link to it
+                let anchor = match id {
+                    Some(id) => id.to_string(),
+                    None => unique_id(&heading_text(&events[index..]), &mut used_ids),
+                };
+
+                let class_attr = if classes.is_empty() {
+                    String::new()
+                } else {
+                    format!(" class=\"{}\"", classes.join(" "))
+                };
+
+                output.push(Event::Html(CowStr::from(format!(
+                    "<{} id=\"{}\"{}>",
+                    level, anchor, class_attr
+                ))));
+            }
+            Event::Start(Tag::Link(link_type, dest, title)) => {
+                let dest = match rewrite_chapter_link(dest) {
+                    Some(rewritten) => CowStr::from(rewritten),
+                    None => dest.clone(),
+                };
+                links.push((dest.to_string(), offset));
+                output.push(Event::Start(Tag::Link(*link_type, dest, title.clone())));
+            }
+            Event::Start(Tag::Image(_, dest, _)) => {
+                links.push((dest.to_string(), offset));
+                output.push(event.clone());
+            }
+            _ => output.push(event.clone()),
+        }
+    }
+
+    let mut xhtml_content = String::new();
+    html::push_html(&mut xhtml_content, output.into_iter());
+    (xhtml_content, links)
+}
+
+fn block_kind(info: &str) -> &str {
+    info.split_whitespace().next().unwrap_or_default()
+}
+
+// Collect the text of a code block, returning it with the number of events
+// consumed including the closing tag
+fn code_block_text(events: &[Event]) -> (String, usize) {
+    let mut text = String::new();
+
+    for (i, event) in events.iter().enumerate() {
+        match event {
+            Event::End(Tag::CodeBlock(_)) => return (text, i + 1),
+            Event::Text(t) => text.push_str(t),
+            _ => {}
+        }
+    }
+
+    (text, events.len())
+}
+
+// A ```chat block holds `Name: message` lines. The sender named after `chat`
+// in the info string (or else the first sender) is shown as the local side.
+// Lines in parentheses are status lines, such as times or "Read 9:41";
+// any other line continues the previous message.
+fn render_chat(text: &str, info: &str) -> String {
+    let mut local_sender = info.split_whitespace().nth(1).map(String::from);
+    let mut messages: Vec<(Option<String>, Vec<String>)> = Vec::new();
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if line.starts_with('(') && line.ends_with(')') {
+            messages.push((None, vec![line[1..line.len() - 1].trim().to_string()]));
+            continue;
+        }
+
+        match line.split_once(':') {
+            Some((sender, message)) if is_sender_name(sender) => {
+                let sender = sender.trim().to_string();
+                if local_sender.is_none() {
+                    local_sender = Some(sender.clone());
+                }
+                messages.push((Some(sender), vec![message.trim().to_string()]));
+            }
+            _ => match messages.last_mut() {
+                Some((Some(_), lines)) => lines.push(line.to_string()),
+                _ => messages.push((None, vec![line.to_string()])),
+            },
+        }
+    }
+
+    let mut output = String::from("<div class=\"chat\">\n");
+    for (sender, lines) in messages {
+        let body = lines
+            .iter()
+            .map(|line| render_inline(line))
+            .collect::<Vec<String>>()
+            .join("<br />");
+
+        match sender {
+            Some(sender) => {
+                let side = if Some(&sender) == local_sender.as_ref() { "chat-sent" } else { "chat-received" };
+                output.push_str(&format!(
+                    "<p class=\"chat-message {}\"><strong class=\"chat-sender\">{}:</strong> <span class=\"chat-text\">{}</span></p>\n",
+                    side,
+                    escape_html(&sender),
+                    body
+                ));
+            }
+            None => output.push_str(&format!("<p class=\"chat-meta\">{}</p>\n", body)),
+        }
+    }
+    output.push_str("</div>\n");
+
+    output
+}
+
+fn is_verse(info: &str) -> bool {
+    matches!(block_kind(info), "poem" | "verse" | "lyrics")
+}
+
+// A ```poem (or ```verse, ```lyrics) block keeps one line per source line.
+// Leading spaces set the indentation level (two spaces per level, a tab
+// counts as two levels), blank lines separate stanzas, and `numbered` or
+// `numbered=N` in the info string numbers every Nth line (default 5).
+fn render_verse(text: &str, info: &str) -> String {
+    let mut numbering: Option<usize> = None;
+    for option in info.split_whitespace().skip(1) {
+        if option == "numbered" {
+            numbering = Some(5);
+        } else if let Some(interval) = option.strip_prefix("numbered=") {
+            numbering = interval.parse().ok().filter(|&n| n > 0);
+        }
+    }
+
+    let mut stanzas: Vec<Vec<&str>> = vec![Vec::new()];
+    for line in text.lines() {
+        if line.trim().is_empty() {
+            if !stanzas.last().unwrap().is_empty() {
+                stanzas.push(Vec::new());
+            }
+        } else {
+            stanzas.last_mut().unwrap().push(line);
+        }
+    }
+    stanzas.retain(|stanza| !stanza.is_empty());
+
+    let mut output = format!("<div class=\"{}\" epub:type=\"z3998:poem\">\n", block_kind(info));
+    let mut line_number = 0;
+
+    for stanza in stanzas {
+        output.push_str("<p class=\"stanza\">\n");
+
+        for line in stanza {
+            line_number += 1;
+
+            let indent: usize = line
+                .chars()
+                .take_while(|c| c.is_whitespace())
+                .map(|c| if c == '\t' { 4 } else { 1 })
+                .sum::<usize>()
+                / 2;
+
+            let class = if indent > 0 {
+                format!("line indent-{}", indent.min(6))
+            } else {
+                String::from("line")
+            };
+
+            let number = match numbering {
+                Some(interval) if line_number % interval == 0 => {
+                    format!("<span class=\"line-number\">{}</span>", line_number)
+                }
+                _ => String::new(),
+            };
+
+            output.push_str(&format!(
+                "<span class=\"{}\">{}{}</span><br />\n",
+                class,
+                render_inline(line.trim()),
+                number
+            ));
+        }
+
+        output.push_str("</p>\n");
+    }
+    output.push_str("</div>\n");
+
+    output
+}
+
+fn is_sender_name(name: &str) -> bool {
+    let name = name.trim();
+    !name.is_empty() && name.chars().count() <= 40 && !name.contains("://")
+}
+
+// Render a single line of inline markdown without the paragraph around it
+fn render_inline(text: &str) -> String {
+    let mut output = String::new();
+    let text = escape_block_start(text);
+    html::push_html(&mut output, Parser::new_ext(&text, markdown_options()));
+
+    let output = output.trim_end();
+    output
+        .strip_prefix("<p>")
+        .and_then(|inner| inner.strip_suffix("</p>"))
+        .unwrap_or(output)
+        .to_string()
+}
+
+// Stop a line such as `1. Then` or `- and` from being read as a list,
+// heading or block quote when it is rendered on its own. A marker only
+// starts a block when white space follows it, so `*sigh*` keeps its emphasis.
+fn escape_block_start(line: &str) -> String {
+    let starts_block = |rest: &str| rest.is_empty() || rest.starts_with(char::is_whitespace);
+
+    let hashes = line.chars().take_while(|&c| c == '#').count();
+    if line.starts_with('>') || ((1..=6).contains(&hashes) && starts_block(&line[hashes..])) {
+        return format!("\\{}", line);
+    }
+
+    if line.starts_with(['-', '+', '*']) && (starts_block(&line[1..]) || is_thematic_break(line)) {
+        return format!("\\{}", line);
+    }
+
+    let digits = line.chars().take_while(|c| c.is_ascii_digit()).count();
+    if (1..=9).contains(&digits) && line[digits..].starts_with(['.', ')']) && starts_block(&line[digits + 1..]) {
+        return format!("{}\\{}", &line[..digits], &line[digits..]);
+    }
+
+    line.to_string()
+}
+
+// `***`, `- - -` and the like, which would render as a rule
+fn is_thematic_break(line: &str) -> bool {
+    let marks: Vec<char> = line.chars().filter(|c| !c.is_whitespace()).collect();
+    marks.len() >= 3 && marks.iter().all(|&c| c == marks[0]) && matches!(marks[0], '-' | '*' | '_')
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+// Rewrite a link to a markdown chapter (`030-chapter-3.md#the-fight`) into
+// the name of the XHTML file generated for it (`030-chapter-3.xhtml#the-fight`)
+pub fn rewrite_chapter_link(dest: &str) -> Option<String> {
+    if is_external(dest) {
+        return None;
+    }
+
+    let (path, fragment) = match dest.split_once('#') {
+        Some((path, fragment)) => (path, Some(fragment)),
+        None => (dest, None),
+    };
+
+    if !path.ends_with(".md") {
+        return None;
+    }
+
+    // [x](My%20Chapter.md) names My Chapter.md
+    let file = sanitize_name(&get_file_name(&percent_decode(path)));
+
+    Some(match fragment {
+        Some(fragment) => format!("{}.xhtml#{}", file, fragment),
+        None => format!("{}.xhtml", file),
+    })
+}
+
+pub fn is_external(href: &str) -> bool {
+    match href.find(':') {
+        Some(colon) => !href[..colon].contains(['/', '#', '?']),
+        None => false,
+    }
+}
+
+fn heading_text(events: &[Event]) -> String {
+    let mut text = String::new();
+
+    for event in events {
+        match event {
+            Event::End(Tag::Heading(..)) => break,
+            Event::Text(t) | Event::Code(t) => text.push_str(t),
+            _ => {}
+        }
+    }
+
+    text
+}
+
+fn unique_id(text: &str, used_ids: &mut HashSet<String>) -> String {
+    let mut base = sanitize_name(text);
+    base = base.trim_start_matches('-').to_string();
+    if base.is_empty() {
+        base = String::from("section");
+    }
+
+    let mut id = base.clone();
+    let mut count = 0;
+    while used_ids.contains(&id) {
+        count += 1;
+        id = format!("{}-{}", base, count);
+    }
+
+    used_ids.insert(id.clone());
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(markdown: &str) -> String {
+        render_events(Parser::new_ext(markdown, markdown_options()).into_offset_iter()).0
+    }
+
+    #[test]
+    fn chapter_links() {
+        let cases = [
+            ("030-chapter-3.md", Some("030-chapter-3.xhtml")),
+            ("030-chapter-3.md#the-fight", Some("030-chapter-3.xhtml#the-fight")),
+            ("./My%20Chapter.md", Some("my-chapter.xhtml")),
+            ("My Chapter.md", Some("my-chapter.xhtml")),
+            ("https://example.com/a.md", None),
+            ("images/a.png", None),
+            ("#the-fight", None),
+        ];
+        for (dest, expected) in cases {
+            assert_eq!(rewrite_chapter_link(dest).as_deref(), expected, "{}", dest);
+        }
+    }
+
+    #[test]
+    fn heading_ids_skip_explicit_ones() {
+        let xhtml = render("# Intro\n\n## Setup {#intro}\n\n# Intro\n");
+        assert!(xhtml.contains(r#"<h1 id="intro-1">Intro</h1>"#), "{}", xhtml);
+        assert!(xhtml.contains(r#"<h2 id="intro">Setup</h2>"#), "{}", xhtml);
+        assert!(xhtml.contains(r#"<h1 id="intro-2">Intro</h1>"#), "{}", xhtml);
+    }
+
+    #[test]
+    fn block_markers_are_escaped_only_before_white_space() {
+        assert_eq!(render_inline("*sigh*"), "<em>sigh</em>");
+        assert_eq!(render_inline("**no** way"), "<strong>no</strong> way");
+        assert_eq!(render_inline("- and then"), "- and then");
+        assert_eq!(render_inline("* * *"), "* * *");
+        assert_eq!(render_inline("# not a heading"), "# not a heading");
+        assert_eq!(render_inline("#hashtag"), "#hashtag");
+        assert_eq!(render_inline("> quoted"), "&gt; quoted");
+        assert_eq!(render_inline("1. Then"), "1. Then");
+        assert_eq!(render_inline("2) Also"), "2) Also");
+        assert_eq!(render_inline("3.14 is pi"), "3.14 is pi");
+    }
+
+    #[test]
+    fn chat_message_keeps_emphasis() {
+        let xhtml = render_chat("Alice: *sigh*\n", "chat");
+        assert!(xhtml.contains(r#"<span class="chat-text"><em>sigh</em></span>"#), "{}", xhtml);
+    }
+
+    #[test]
+    fn chat_message_spans_lines() {
+        let xhtml = render_chat("Alice: Are you there?\nI waited all day.\n- and night\nBob: Sorry\n", "chat");
+        assert!(
+            xhtml.contains(r#"<span class="chat-text">Are you there?<br />I waited all day.<br />- and night</span>"#),
+            "{}",
+            xhtml
+        );
+        assert!(xhtml.contains(r#"<span class="chat-text">Sorry</span>"#), "{}", xhtml);
+    }
+
+    #[test]
+    fn chat_senders_are_aligned() {
+        // the first sender is the local side, unless the info string names one
+        let xhtml = render_chat("Alice: Hi\n(9:41)\nBob: Hello\n", "chat");
+        assert!(xhtml.contains(r#"<p class="chat-message chat-sent"><strong class="chat-sender">Alice:</strong>"#), "{}", xhtml);
+        assert!(xhtml.contains(r#"<p class="chat-meta">9:41</p>"#), "{}", xhtml);
+        assert!(xhtml.contains(r#"<p class="chat-message chat-received"><strong class="chat-sender">Bob:</strong>"#), "{}", xhtml);
+
+        let xhtml = render_chat("Alice: Hi\nBob: Hello\n", "chat Bob");
+        assert!(xhtml.contains(r#"<p class="chat-message chat-received"><strong class="chat-sender">Alice:</strong>"#), "{}", xhtml);
+        assert!(xhtml.contains(r#"<p class="chat-message chat-sent"><strong class="chat-sender">Bob:</strong>"#), "{}", xhtml);
+    }
+
+    #[test]
+    fn verse_line_keeps_emphasis() {
+        let xhtml = render_verse("*Whose woods* these are I think I know.\n", "poem");
+        assert!(xhtml.contains(r#"<span class="line"><em>Whose woods</em> these are I think I know.</span><br />"#), "{}", xhtml);
+    }
+
+    #[test]
+    fn verse_indentation() {
+        let xhtml = render_verse("Level none\n  Level one\n    Level two\n\tTab\n                Deep\n", "poem");
+        assert!(xhtml.contains(r#"<span class="line">Level none</span>"#), "{}", xhtml);
+        assert!(xhtml.contains(r#"<span class="line indent-1">Level one</span>"#), "{}", xhtml);
+        assert!(xhtml.contains(r#"<span class="line indent-2">Level two</span>"#), "{}", xhtml);
+        assert!(xhtml.contains(r#"<span class="line indent-2">Tab</span>"#), "{}", xhtml);
+        assert!(xhtml.contains(r#"<span class="line indent-6">Deep</span>"#), "{}", xhtml);
+    }
+
+    #[test]
+    fn verse_stanzas() {
+        let xhtml = render_verse("\nOne\nTwo\n\n\n\nThree\n\n", "verse");
+        assert!(xhtml.starts_with(r#"<div class="verse" epub:type="z3998:poem">"#), "{}", xhtml);
+        assert_eq!(xhtml.matches(r#"<p class="stanza">"#).count(), 2, "{}", xhtml);
+        assert!(xhtml.contains("<span class=\"line\">Two</span><br />\n</p>\n<p class=\"stanza\">\n<span class=\"line\">Three</span>"), "{}", xhtml);
+    }
+
+    #[test]
+    fn verse_line_numbers() {
+        let poem = "a\nb\nc\n\nd\ne\nf\n";
+        let numbered = |info: &str| -> Vec<String> {
+            render_verse(poem, info)
+                .split(r#"<span class="line-number">"#)
+                .skip(1)
+                .map(|rest| rest[..rest.find('<').unwrap()].to_string())
+                .collect()
+        };
+
+        assert!(numbered("poem").is_empty());
+        assert_eq!(numbered("poem numbered"), ["5"]);
+        // numbering runs on across stanzas
+        assert_eq!(numbered("poem numbered=2"), ["2", "4", "6"]);
+        assert!(numbered("poem numbered=0").is_empty());
+    }
+}
@@ -0,0 +1,286 @@
+use std::fs;
+use std::path::Path;
+
+use regex::Regex;
+
+use crate::diagnostics::Diagnostic;
+use crate::fonts::Font;
+use crate::types::{Align, EpubInfo, HeadingStyle, Theme};
+
+// Families every reading system knows, which need no font file
+const GENERIC_FAMILIES: &[&str] = &[
+    "serif", "sans-serif", "monospace", "cursive", "fantasy", "system-ui", "inherit", "initial",
+];
+
+// Values of a theme template, before the book's own are filled in
+struct Defaults {
+    font: &'static str,
+    // added after a font of the book's, for when it cannot be used
+    fallback: &'static str,
+    font_size: &'static str,
+    line_height: &'static str,
+    indent: &'static str,
+    paragraph_spacing: &'static str,
+    text_align: &'static str,
+    headings: Option<HeadingStyle>,
+}
+
+fn defaults(theme: Theme) -> Defaults {
+    match theme {
+        Theme::Classic | Theme::None => Defaults {
+            font: r#"Georgia, "Times New Roman", serif"#,
+            fallback: "serif",
+            font_size: "1em",
+            line_height: "1.4",
+            indent: "1.5em",
+            paragraph_spacing: "0",
+            text_align: "justify",
+            headings: Some(HeadingStyle::SmallCaps),
+        },
+        Theme::Modern => Defaults {
+            font: r#""Helvetica Neue", Helvetica, Arial, sans-serif"#,
+            fallback: "sans-serif",
+            font_size: "1em",
+            line_height: "1.5",
+            indent: "0",
+            paragraph_spacing: "0.8em",
+            text_align: "left",
+            headings: Some(HeadingStyle::Left),
+        },
+        Theme::Minimal => Defaults {
+            font: "inherit",
+            fallback: "inherit",
+            font_size: "1em",
+            line_height: "inherit",
+            indent: "1.2em",
+            paragraph_spacing: "0",
+            text_align: "inherit",
+            headings: None,
+        },
+    }
+}
+
+fn builtin_template(theme: Theme) -> &'static str {
+    match theme {
+        Theme::Classic | Theme::None => include_str!("css/themes/classic.css"),
+        Theme::Modern => include_str!("css/themes/modern.css"),
+        Theme::Minimal => include_str!("css/themes/minimal.css"),
+    }
+}
+
+// Where the theme goes inside OPS, if the book uses one
+pub fn href(epub_info: &EpubInfo) -> Option<String> {
+    if epub_info.theme.template.is_some() {
+        return Some(String::from("css/theme-custom.css"));
+    }
+
+    let name = match epub_info.theme() {
+        Theme::Classic => "classic",
+        Theme::Modern => "modern",
+        Theme::Minimal => "minimal",
+        Theme::None => return None,
+    };
+    Some(format!("css/theme-{}.css", name))
+}
+
+// Fill in the theme template with the values from book.yaml and write it.
+// Fonts named in book.yaml are looked for among the book's fonts, whose
+// faces are in fonts.css.
+pub fn create_theme_css(epub_info: &EpubInfo, book_folder: &str, dest_folder: &str) {
+    let Some(href) = href(epub_info) else {
+        return;
+    };
+    let config = &epub_info.theme;
+
+    let template = match &config.template {
+        Some(path) => match fs::read_to_string(Path::new(book_folder).join(path)) {
+            Ok(template) => template,
+            Err(err) => {
+                Diagnostic::error(format!("failed to read the theme template {}: {}", path, err)).in_config(path).emit();
+                return;
+            }
+        },
+        None => builtin_template(epub_info.theme()).to_string(),
+    };
+
+    let values = values(epub_info);
+    let (css, unknown) = fill_template(&template, &values);
+    for (placeholder, name) in unknown {
+        let diagnostic = Diagnostic::warning(format!("unknown theme variable {}", name));
+        match &config.template {
+            Some(path) => diagnostic.near(path, &placeholder).emit(),
+            None => diagnostic.emit(),
+        }
+    }
+
+    let css_path = Path::new(dest_folder).join("OPS").join(href);
+    if let Some(parent) = css_path.parent() {
+        let _ = fs::create_dir_all(parent);
+    }
+
+    fs::write(&css_path, css)
+        .unwrap_or_else(|err| Diagnostic::error(format!("failed to write the theme stylesheet: {}", err)).emit());
+}
+
+// The values of the theme variables: the book's own, or the defaults of
+// its base theme
+fn values(epub_info: &EpubInfo) -> Vec<(&'static str, String)> {
+    let config = &epub_info.theme;
+    let defaults = defaults(epub_info.theme());
+
+    let font = match &config.font {
+        Some(font) => font_family(font, defaults.fallback, &epub_info.font_faces),
+        None => defaults.font.to_string(),
+    };
+    let heading_font = match &config.heading_font {
+        Some(font) => font_family(font, defaults.fallback, &epub_info.font_faces),
+        None => String::from("inherit"),
+    };
+
+    let (heading_align, heading_transform, heading_variant) = match config.headings.or(defaults.headings) {
+        Some(HeadingStyle::Centered) => ("center", "none", "normal"),
+        Some(HeadingStyle::Left) => ("left", "none", "normal"),
+        Some(HeadingStyle::SmallCaps) => ("center", "none", "small-caps"),
+        Some(HeadingStyle::Uppercase) => ("center", "uppercase", "normal"),
+        None => ("inherit", "inherit", "inherit"),
+    };
+
+    let text_align = match config.align {
+        Some(Align::Justify) => "justify",
+        Some(Align::Left) => "left",
+        None => defaults.text_align,
+    };
+
+    vec![
+        ("font", font),
+        ("heading_font", heading_font),
+        ("font_size", config.font_size.as_ref().map_or(defaults.font_size.to_string(), |size| size.0.clone())),
+        ("line_height", config.line_height.map_or(defaults.line_height.to_string(), |height| height.to_string())),
+        ("indent", config.indent.as_ref().map_or(defaults.indent.to_string(), |indent| indent.0.clone())),
+        (
+            "paragraph_spacing",
+            config.paragraph_spacing.as_ref().map_or(defaults.paragraph_spacing.to_string(), |spacing| spacing.0.clone()),
+        ),
+        ("text_align", text_align.to_string()),
+        ("heading_align", heading_align.to_string()),
+        ("heading_transform", heading_transform.to_string()),
+        ("heading_variant", heading_variant.to_string()),
+    ]
+}
+
+// Replace the `{{ name }}` placeholders of a template with their values.
+// Unknown variables are left empty and returned, as placeholder and name.
+fn fill_template(template: &str, values: &[(&str, String)]) -> (String, Vec<(String, String)>) {
+    let placeholder_re = Regex::new(r"\{\{\s*([A-Za-z_]+)\s*\}\}").unwrap();
+    let mut unknown = Vec::new();
+    let css = placeholder_re.replace_all(template, |caps: &regex::Captures| {
+        match values.iter().find(|(name, _)| *name == &caps[1]) {
+            Some((_, value)) => value.clone(),
+            None => {
+                unknown.push((caps[0].to_string(), caps[1].to_string()));
+                String::new()
+            }
+        }
+    });
+    (css.into_owned(), unknown)
+}
+
+// The font-family value for a font named in book.yaml. A family that is
+// not one of the book's fonts is reported, since reading systems will
+// show their own font instead.
+fn font_family(value: &str, fallback: &str, fonts: &[Font]) -> String {
+    let family = value.split(',').next().unwrap_or_default().trim().trim_matches(['"', '\'']);
+    if family.is_empty() || GENERIC_FAMILIES.contains(&family.to_lowercase().as_str()) {
+        return value.to_string();
+    }
+
+    let wanted = normalize(family);
+    match fonts.iter().find(|font| normalize(&font.info.family) == wanted) {
+        // spelled as in the font, so it matches fonts.css
+        Some(font) if !value.contains(',') => return format!("\"{}\", {}", font.info.family, fallback),
+        Some(_) => return value.to_string(),
+        None => {}
+    }
+
+    let mut families: Vec<&str> = fonts.iter().map(|font| font.info.family.as_str()).collect();
+    families.dedup();
+    let available = match families.is_empty() {
+        true => String::from("the book has none"),
+        false => format!("the book has {}", families.join(", ")),
+    };
+    Diagnostic::warning(format!(
+        "font \"{}\" is not among the book's fonts ({}), reading systems will use one of their own",
+        family, available
+    ))
+    .in_config(family)
+    .emit();
+
+    if value.contains(',') {
+        value.to_string()
+    } else {
+        format!("\"{}\", {}", family, fallback)
+    }
+}
+
+// "cormorant garamond" and "Cormorant Garamond" compare alike
+fn normalize(name: &str) -> String {
+    name.chars().filter(|c| c.is_alphanumeric()).flat_map(char::to_lowercase).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A book with a stylesheet of its own and the given theme section
+    fn styled_book(theme: &str) -> EpubInfo {
+        let mut epub_info: EpubInfo = serde_yaml::from_str(&format!("name: test\nauthor: Me\ntitle: Test\n{}", theme)).unwrap();
+        epub_info.stylesheets = vec![String::from("css/book.css")];
+        epub_info
+    }
+
+    #[test]
+    fn variables_select_the_classic_theme() {
+        assert_eq!(styled_book("theme:\n  font: Gelasio\n").theme(), Theme::Classic);
+        assert_eq!(href(&styled_book("theme:\n  line_height: 1.6\n")).as_deref(), Some("css/theme-classic.css"));
+        // the ornament goes into the pages, not the stylesheet
+        assert_eq!(styled_book("theme:\n  ornament: \"~\"\n").theme(), Theme::None);
+        assert_eq!(href(&styled_book("")), None);
+    }
+
+    #[test]
+    fn theme_choice() {
+        let plain: EpubInfo = serde_yaml::from_str("name: test\nauthor: Me\ntitle: Test\n").unwrap();
+        assert_eq!(plain.theme(), Theme::Classic);
+        assert_eq!(styled_book("").theme(), Theme::None);
+        // an explicit base wins, with or without book.css
+        assert_eq!(styled_book("theme: modern\n").theme(), Theme::Modern);
+        assert_eq!(styled_book("theme:\n  base: minimal\n  font: serif\n").theme(), Theme::Minimal);
+        let none: EpubInfo = serde_yaml::from_str("name: test\nauthor: Me\ntitle: Test\ntheme: none\n").unwrap();
+        assert_eq!(none.theme(), Theme::None);
+        assert_eq!(href(&none), None);
+        assert_eq!(
+            href(&styled_book("theme:\n  template: css/mine.css\n")).as_deref(),
+            Some("css/theme-custom.css")
+        );
+    }
+
+    #[test]
+    fn builtin_templates_are_filled() {
+        for theme in [Theme::Classic, Theme::Modern, Theme::Minimal] {
+            let epub_info = styled_book(&format!("theme: {}\n", format!("{:?}", theme).to_lowercase()));
+            let (css, unknown) = fill_template(builtin_template(theme), &values(&epub_info));
+            assert!(unknown.is_empty(), "{:?}: {:?}", theme, unknown);
+            assert!(!css.contains("{{") && !css.contains("}}"), "{:?}", theme);
+        }
+    }
+
+    #[test]
+    fn template_values() {
+        let epub_info = styled_book("theme:\n  base: modern\n  font: serif\n  indent: 2em\n  align: justify\n");
+        let template = "p { font-family: {{font}}; text-indent: {{ indent }}; text-align: {{text_align}}; \
+                        line-height: {{line_height}}; margin: {{margin}}; }";
+        let (css, unknown) = fill_template(template, &values(&epub_info));
+        assert_eq!(css, "p { font-family: serif; text-indent: 2em; text-align: justify; line-height: 1.5; margin: ; }");
+        assert_eq!(unknown, [(String::from("{{margin}}"), String::from("margin"))]);
+    }
+}
@@ -1,5 +1,12 @@
+use std::collections::BTreeMap;
+
 use serde_derive::Deserialize;
 
+use crate::audit::Audit;
+use crate::diff::{line_hunks, LineMap};
+use crate::fonts::Font;
+use crate::media::MediaType;
+
 #[derive(Debug, Deserialize)]
 pub struct EpubInfo {
     pub id: Option<String>,
@@ -8,8 +15,481 @@ pub struct EpubInfo {
     pub title: String,
     pub start: Option<String>,
     pub start_title: Option<String>,
-    pub fonts: Option<Vec<String>>,
-    pub images: Option<Vec<String>>
+    // files found next to the chapters, filled in while building
+    #[serde(skip)]
+    pub assets: Vec<Asset>,
+    pub language: Option<String>,
+    #[serde(default)]
+    pub breaks: BreakConfig,
+    #[serde(default)]
+    pub typography: TypographyConfig,
+    #[serde(default)]
+    pub preprocess: PreprocessConfig,
+    #[serde(default)]
+    pub hyphenation: HyphenationConfig,
+    pub audit: Option<AuditConfig>,
+    #[serde(default)]
+    pub files: FilesConfig,
+    // stylesheets every page links, relative to the book folder; book.css
+    // when not given
+    pub styles: Option<Vec<String>>,
+    // where those stylesheets are inside OPS, filled in while building
+    #[serde(skip)]
+    pub stylesheets: Vec<String>,
+    #[serde(default)]
+    pub fonts: FontsConfig,
+    // faces of the book's fonts, filled in while building
+    #[serde(skip)]
+    pub font_faces: Vec<Font>,
+    // built-in stylesheet laid under the book's own, and its variables
+    #[serde(default)]
+    pub theme: ThemeConfig,
+}
+
+impl EpubInfo {
+    pub fn language(&self) -> &str {
+        self.language.as_deref().filter(|lang| !lang.is_empty()).unwrap_or("en")
+    }
+
+    // The theme in use: classic for a book with no stylesheet of its own or
+    // one that sets theme variables, none for one that only has stylesheets
+    pub fn theme(&self) -> Theme {
+        match self.theme.base {
+            Some(theme) => theme,
+            None if self.stylesheets.is_empty() || self.theme.has_variables() => Theme::Classic,
+            None => Theme::None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    // serif, indented paragraphs, for fiction
+    Classic,
+    // sans-serif, spaced paragraphs, for nonfiction
+    Modern,
+    // layout of the generated markup only
+    Minimal,
+    None,
+}
+
+// `theme: classic`, or a section filling in the variables of a theme
+// template. Values left out take the defaults of the base theme.
+#[derive(Debug, Default)]
+pub struct ThemeConfig {
+    pub base: Option<Theme>,
+    // a template of the book's own, relative to the book folder
+    pub template: Option<String>,
+    pub font: Option<String>,
+    pub heading_font: Option<String>,
+    pub font_size: Option<Length>,
+    pub line_height: Option<f32>,
+    // first-line indent of paragraphs; 0 for spaced paragraphs
+    pub indent: Option<Length>,
+    pub paragraph_spacing: Option<Length>,
+    pub align: Option<Align>,
+    pub headings: Option<HeadingStyle>,
+    // text of scene breaks, in place of breaks.ornament; the breaks rule
+    // writes it into the pages, so templates have no variable for it
+    pub ornament: Option<String>,
+}
+
+impl ThemeConfig {
+    // Whether any of the template variables is set
+    fn has_variables(&self) -> bool {
+        self.font.is_some()
+            || self.heading_font.is_some()
+            || self.font_size.is_some()
+            || self.line_height.is_some()
+            || self.indent.is_some()
+            || self.paragraph_spacing.is_some()
+            || self.align.is_some()
+            || self.headings.is_some()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ThemeSection {
+    base: Option<Theme>,
+    template: Option<String>,
+    font: Option<String>,
+    heading_font: Option<String>,
+    font_size: Option<Length>,
+    line_height: Option<f32>,
+    indent: Option<Length>,
+    paragraph_spacing: Option<Length>,
+    align: Option<Align>,
+    headings: Option<HeadingStyle>,
+    ornament: Option<String>,
+}
+
+impl<'de> serde::Deserialize<'de> for ThemeConfig {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ThemeVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for ThemeVisitor {
+            type Value = ThemeConfig;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a theme name or a theme section")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, name: &str) -> Result<ThemeConfig, E> {
+                let base: Theme = serde::Deserialize::deserialize(serde::de::value::StrDeserializer::new(name))?;
+                Ok(ThemeConfig { base: Some(base), ..ThemeConfig::default() })
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(self, map: A) -> Result<ThemeConfig, A::Error> {
+                let section: ThemeSection = serde::Deserialize::deserialize(serde::de::value::MapAccessDeserializer::new(map))?;
+                Ok(ThemeConfig {
+                    base: section.base,
+                    template: section.template,
+                    font: section.font,
+                    heading_font: section.heading_font,
+                    font_size: section.font_size,
+                    line_height: section.line_height,
+                    indent: section.indent,
+                    paragraph_spacing: section.paragraph_spacing,
+                    align: section.align,
+                    headings: section.headings,
+                    ornament: section.ornament,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(ThemeVisitor)
+    }
+}
+
+// A CSS length such as 1.5em, 12pt or 0
+#[derive(Debug, Deserialize, Clone)]
+#[serde(try_from = "String")]
+pub struct Length(pub String);
+
+impl TryFrom<String> for Length {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Length, String> {
+        let number = value.trim_end_matches(|c: char| c.is_ascii_alphabetic() || c == '%');
+        let unit = &value[number.len()..];
+        let valid_number = !number.is_empty() && number.parse::<f32>().is_ok_and(|n| n >= 0.0);
+        let valid_unit = match unit {
+            "" => number.parse::<f32>() == Ok(0.0),
+            unit => ["em", "rem", "ex", "ch", "px", "pt", "pc", "mm", "cm", "in", "%", "vw", "vh"].contains(&unit),
+        };
+
+        if valid_number && valid_unit {
+            Ok(Length(value))
+        } else {
+            Err(format!("\"{}\" is not a CSS length such as 1.5em or 12pt", value))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Align {
+    Justify,
+    Left,
+}
+
+// How chapter headings are set
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum HeadingStyle {
+    Centered,
+    Left,
+    SmallCaps,
+    Uppercase,
+}
+
+// Scene breaks: which markdown line marks one and how it is rendered
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct BreakConfig {
+    // "----", "***", "* * *" or "#"
+    pub marker: String,
+    pub style: BreakStyle,
+    // text shown by the text style, and alt text for the image style
+    pub ornament: String,
+    // image used by the image style, relative to the book folder
+    pub image: Option<String>,
+    pub spacing: BreakSpacing,
+    pub epub_type: String,
+}
+
+impl Default for BreakConfig {
+    fn default() -> Self {
+        BreakConfig {
+            marker: String::from("----"),
+            style: BreakStyle::Text,
+            ornament: String::from("***"),
+            image: None,
+            spacing: BreakSpacing::Nbsp,
+            epub_type: String::from("z3998:transition"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BreakStyle {
+    // <div class="center">***</div>
+    Text,
+    // <div class="center"><img .../></div>
+    Image,
+    // <hr class="scene-break" />
+    Rule,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BreakSpacing {
+    // empty &nbsp; paragraphs before and after the break
+    Nbsp,
+    // no extra markup, spacing comes from the .scene-break rule in the stylesheet
+    Css,
+}
+
+// Quotation and punctuation rules. By default they follow the book
+// language; each setting here overrides the chosen rule set.
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct TypographyConfig {
+    // rule set: en-us, en-gb, de, de-guillemets, fr or sl
+    pub quotes: Option<String>,
+    // opening and closing marks, e.g. "»«"
+    pub primary: Option<String>,
+    pub secondary: Option<String>,
+    pub punctuation: Option<Punctuation>,
+    // narrow no-break space inside quotes and before ; : ! ?
+    pub quote_spacing: Option<bool>,
+    // space before an ellipsis, for the ellipses rule
+    pub ellipsis: EllipsisSpacing,
+    // words followed by a no-break space, and units preceded by one, for
+    // the nbsp rule; a list here replaces the default one
+    pub honorifics: Option<Vec<String>>,
+    pub units: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EllipsisSpacing {
+    // as the author typed it
+    #[default]
+    Keep,
+    // no space: "wait…"
+    Tight,
+    // a no-break space, so the ellipsis never starts a line: "wait …"
+    Nbsp,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Punctuation {
+    // periods and commas go inside the closing quote
+    American,
+    // punctuation stays where the author put it
+    Logical,
+}
+
+// Soft hyphens inserted by the hyphenate rule
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct HyphenationConfig {
+    // Liang/TeX pattern file relative to the book folder; by default
+    // hyphenation/hyph-<language>.tex
+    pub patterns: Option<String>,
+    // shortest word that gets hyphenated
+    pub min_word: usize,
+    // fewest letters left before and after a hyphen
+    pub left_min: usize,
+    pub right_min: usize,
+}
+
+impl Default for HyphenationConfig {
+    fn default() -> Self {
+        HyphenationConfig {
+            patterns: None,
+            min_word: 6,
+            left_min: 2,
+            right_min: 3,
+        }
+    }
+}
+
+// Which preprocessing rules run, in what order, and any find/replace
+// rules of the book's own
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct PreprocessConfig {
+    // rule names in the order they run; the default chain when left out
+    pub rules: Option<Vec<String>>,
+    pub replace: Vec<ReplaceRule>,
+    pub external: Vec<ExternalPreprocessor>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReplaceRule {
+    pub name: String,
+    // regular expression; the replacement can use $1, $2...
+    pub find: String,
+    pub replace: String,
+    // only match whole words
+    #[serde(default)]
+    pub word: bool,
+    #[serde(default)]
+    pub ignore_case: bool,
+}
+
+// A program run over the markdown, see external.rs for what it receives
+#[derive(Debug, Deserialize)]
+pub struct ExternalPreprocessor {
+    pub name: String,
+    // path relative to the book folder, or a program on the PATH
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub scope: Scope,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    // run once per chapter, in the order given by `rules`
+    #[default]
+    Chapter,
+    // run once with every chapter, after the chapter rules
+    Book,
+}
+
+// Report of every change the preprocessing rules made
+#[derive(Debug, Deserialize)]
+pub struct AuditConfig {
+    #[serde(default)]
+    pub format: AuditFormat,
+    // where to write it; next to the EPUB as <name>-audit.html by default
+    pub path: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditFormat {
+    #[default]
+    Html,
+    Json,
+}
+
+// Which files of the book folder are copied into the book. Globs are
+// matched against paths relative to the book folder; `*` stays within a
+// folder, `**` crosses folders, and a glob without a `/` matches the file
+// name anywhere. Hidden files and folders are always left out.
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct FilesConfig {
+    // everything with a known media type when empty
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    // copy files no page or stylesheet refers to as well
+    pub keep_unused: bool,
+}
+
+// What is done to the book's fonts
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct FontsConfig {
+    // cut the TTF and OTF fonts down to the characters the book uses
+    pub subset: bool,
+    // characters to keep in every subset besides those in the text
+    pub keep: String,
+    // fontTools' subsetter, or a script of the book folder taking the same arguments
+    pub command: String,
+    // obfuscation of every font, for typefaces licensed on that condition
+    pub obfuscate: Obfuscation,
+    // obfuscation of single fonts, by path relative to the book folder
+    pub obfuscate_files: BTreeMap<String, Obfuscation>,
+    // what to do with a font whose licence does not allow embedding
+    pub embedding: EmbeddingCheck,
+}
+
+impl Default for FontsConfig {
+    fn default() -> Self {
+        FontsConfig {
+            subset: false,
+            keep: String::new(),
+            command: String::from("pyftsubset"),
+            obfuscate: Obfuscation::None,
+            obfuscate_files: BTreeMap::new(),
+            embedding: EmbeddingCheck::Warn,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EmbeddingCheck {
+    Ignore,
+    Warn,
+    // report an error, so the build fails
+    Error,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Obfuscation {
+    None,
+    // the algorithm of the EPUB specification
+    Idpf,
+    // Adobe's older algorithm, for reading systems built on Adobe's SDK
+    Adobe,
+}
+
+// A file copied into the book: image, font, stylesheet, script, audio or video
+#[derive(Debug)]
+pub struct Asset {
+    // path relative to the book folder
+    pub source: String,
+    // path inside OPS, such as images/cover.jpg
+    pub href: String,
+    // manifest id, unique in the book
+    pub id: String,
+    pub media_type: &'static MediaType,
+    // manifest id of the item reading systems fall back to for a non-core type
+    pub fallback: Option<String>,
+}
+
+// A chapter's markdown as it goes through preprocessing
+pub struct Source {
+    pub name: String,
+    // path relative to the book folder
+    pub path: String,
+    pub content: String,
+    pub line_map: LineMap,
+}
+
+impl Source {
+    pub fn new(name: String, path: String, content: String) -> Source {
+        let line_map = LineMap::new(&content);
+        Source { name, path, content, line_map }
+    }
+
+    // Replace the content with what `rule` made of it, keeping track of
+    // where its lines came from
+    pub fn update(&mut self, rule: &str, content: String, audit: Option<&mut Audit>) {
+        if content == self.content {
+            return;
+        }
+
+        let hunks = line_hunks(&self.content, &content);
+        if let Some(audit) = audit {
+            audit.record(&self.path, rule, &self.content, &content, &self.line_map, &hunks);
+        }
+        self.line_map.apply(&self.content, &content, &hunks);
+        self.content = content;
+    }
 }
 
 #[derive(Clone)]
@@ -18,4 +498,25 @@ pub struct Page {
     pub file: String,
     pub title: String,
     pub body: String,
-}
\ No newline at end of file
+    // the chapter file, relative to the book folder
+    pub source: String,
+    pub links: Vec<SourceLink>,
+    // stylesheets the chapter links besides those of the book, inside OPS
+    pub styles: Vec<String>,
+}
+
+// The YAML block between `---` lines at the top of a chapter
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct FrontMatter {
+    // stylesheets for this chapter only, relative to the book folder
+    pub styles: Vec<String>,
+}
+
+// A link or image as written to the XHTML, and where it is in the chapter file
+#[derive(Clone)]
+pub struct SourceLink {
+    pub href: String,
+    pub line: usize,
+    pub column: usize,
+}
@@ -1,12 +1,12 @@
-use std::fs::{self, DirEntry};
-use std::io::Write;
-use std::path::{Path, PathBuf};
-use serde_yaml;
+use std::fs;
+use std::path::Path;
+
+use crate::diagnostics::Diagnostic;
 
 pub fn create_file(file_path: &Path, file_content: String) {
-    let mut file = fs::File::create(file_path).expect("Failed to create file");
-    file.write_all(file_content.as_bytes())
-        .expect("Failed to write to file");
+    if let Err(err) = fs::write(file_path, file_content) {
+        Diagnostic::error(format!("failed to write {}: {}", file_path.display(), err)).emit();
+    }
 }
 
 
@@ -56,6 +56,124 @@ pub fn get_file_name(source: &str) -> String {
         .file_stem()
         .and_then(|stem| stem.to_str())
         .map(|name| name.to_string())
-        .unwrap_or_else(|| String::new())
+        .unwrap_or_default()
+}
+
+
+// Match a path relative to the book folder against a glob: `?` is one
+// character, `*` any run of characters but `/`, and `**` any run of folders.
+// A glob without a `/` is matched against the file name alone.
+pub fn glob_match(glob: &str, path: &str) -> bool {
+    let path = if glob.contains('/') {
+        path
+    } else {
+        path.rsplit('/').next().unwrap_or(path)
+    };
+
+    let glob: Vec<char> = glob.chars().collect();
+    let path: Vec<char> = path.chars().collect();
+    match_from(&glob, &path)
+}
+
+fn match_from(glob: &[char], path: &[char]) -> bool {
+    match glob {
+        [] => path.is_empty(),
+        // `**/` also matches no folder at all
+        ['*', '*', '/', rest @ ..] => {
+            match_from(rest, path)
+                || (0..path.len()).any(|i| path[i] == '/' && match_from(rest, &path[i + 1..]))
+        }
+        ['*', '*', rest @ ..] => (0..=path.len()).any(|i| match_from(rest, &path[i..])),
+        ['*', rest @ ..] => {
+            let run = path.iter().position(|&c| c == '/').unwrap_or(path.len());
+            (0..=run).any(|i| match_from(rest, &path[i..]))
+        }
+        ['?', rest @ ..] => matches!(path.first(), Some(&c) if c != '/') && match_from(rest, &path[1..]),
+        [c, rest @ ..] => path.first() == Some(c) && match_from(rest, &path[1..]),
+    }
 }
 
+// Decode %XX escapes in a URL path, as written by the markdown renderer
+pub fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| std::str::from_utf8(hex).ok());
+        match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(byte) if bytes[i] == b'%' => {
+                output.push(byte);
+                i += 3;
+            }
+            _ => {
+                output.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+// Take the YAML front matter off the top of a chapter, returning it with the
+// chapter. Its lines are left blank so line numbers stay the same.
+pub fn split_front_matter(markdown: &str) -> Option<(String, String)> {
+    let mut lines = markdown.split_inclusive('\n');
+    if lines.next()?.trim_end() != "---" {
+        return None;
+    }
+
+    let mut yaml = String::new();
+    let mut blanked = String::from("\n");
+    for line in lines.by_ref() {
+        blanked.push('\n');
+        if line.trim_end() == "---" {
+            blanked.extend(lines);
+            return Some((yaml, blanked));
+        }
+        yaml.push_str(line);
+    }
+
+    // no closing line, so it was a rule rather than front matter
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+
+    #[test]
+    fn star_stays_in_one_folder() {
+        assert!(glob_match("images/*.png", "images/a.png"));
+        assert!(glob_match("images/*", "images/a.png"));
+        assert!(!glob_match("images/*.png", "images/maps/a.png"));
+        assert!(!glob_match("*/a.png", "images/maps/a.png"));
+        assert!(glob_match("*.png", "images/maps/a.png"), "a glob without / matches the file name");
+        assert!(glob_match("cover*", "cover.jpg"));
+        assert!(glob_match("*", "cover.jpg"));
+        assert!(!glob_match("*.png", "a.jpg"));
+    }
+
+    #[test]
+    fn double_star_crosses_folders() {
+        assert!(glob_match("**/*.png", "a.png"));
+        assert!(glob_match("**/*.png", "images/maps/a.png"));
+        assert!(glob_match("images/**/*.png", "images/a.png"));
+        assert!(glob_match("images/**/*.png", "images/maps/old/a.png"));
+        assert!(!glob_match("images/**/*.png", "art/images/a.png"));
+        assert!(glob_match("drafts/**", "drafts/a/b.md"));
+        assert!(!glob_match("drafts/**", "notes/drafts.md"));
+    }
+
+    #[test]
+    fn question_mark_is_one_character() {
+        assert!(glob_match("page-?.png", "page-1.png"));
+        assert!(!glob_match("page-?.png", "page-10.png"));
+        assert!(!glob_match("page-?.png", "page-.png"));
+        assert!(!glob_match("images?a.png", "images/a.png"));
+    }
+}